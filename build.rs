@@ -0,0 +1,14 @@
+//! Compiles syntect's default syntaxes plus the grammars in syntaxes/ into one
+//! dump. Linking them at startup instead would cost every run a noticeable
+//! delay; loading the dump is as fast as loading the defaults alone.
+
+use std::path::Path;
+use syntect::parsing::SyntaxSet;
+
+fn main() {
+    println!("cargo:rerun-if-changed=syntaxes");
+    let mut builder = SyntaxSet::load_defaults_newlines().into_builder();
+    builder.add_from_folder("syntaxes", true).expect("loading syntaxes/");
+    let out = Path::new(&std::env::var("OUT_DIR").expect("OUT_DIR is set")).join("syntaxes.packdump");
+    syntect::dumps::dump_to_file(&builder.build(), out).expect("writing the syntax dump");
+}
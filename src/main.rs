@@ -1,19 +1,34 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
-use std::io::Read;
-use std::path::Path;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 
 use anyhow::{anyhow, Context, Result};
-use clap::{Parser, Subcommand};
-use pulldown_cmark::{html, CodeBlockKind, CowStr, Event, Options, Parser as MdParser, Tag, TagEnd};
-use tiny_http::{Header, Response, Server};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use pulldown_cmark::{html, CodeBlockKind, CowStr, Event, HeadingLevel, Options, Parser as MdParser, Tag, TagEnd};
+use tiny_http::{Header, Method, Request, Response, Server, SslConfig};
 use walkdir::WalkDir;
-use orgize::Org;
-use once_cell::sync::Lazy;
+use orgize::export::{DefaultHtmlHandler, HtmlHandler};
+use orgize::elements::{Link, Table};
+use orgize::{Element, Event as OrgEvent, Org};
+use latex2mathml::{latex_to_mathml, DisplayStyle};
+use notify::Watcher;
+use once_cell::sync::{Lazy, OnceCell};
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use syntect::html::{css_for_theme_with_class_style, ClassStyle, ClassedHTMLGenerator};
 use syntect::highlighting::{Theme, ThemeSet};
-use syntect::parsing::{SyntaxReference, SyntaxSet};
+use syntect::parsing::{SyntaxDefinition, SyntaxReference, SyntaxSet};
 use syntect::util::LinesWithEndings;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+use yaml_rust::{Yaml, YamlLoader};
 
 #[derive(Parser, Debug)]
 #[command(name = "haystack", version, about = "Build and serve markdown/org to HTML")]
@@ -26,131 +41,2131 @@ struct Cli {
 enum Commands {
     /// Compile src/*.md and src/*.org to output/*.html
     Build {
-        /// Light theme name for syntax highlighting (syntect)
-        #[arg(long, value_name = "NAME")]
-        theme_light: Option<String>,
-        /// Dark theme name for syntax highlighting (syntect)
-        #[arg(long, value_name = "NAME")]
-        theme_dark: Option<String>,
+        /// Don't write output/.haystack-manifest.json
+        #[arg(long)]
+        no_manifest: bool,
+        /// Also write every page into one self-contained output/all.html
+        #[arg(long)]
+        single_page: bool,
+        /// Treat warnings (such as two sources producing the same output file) as errors
+        #[arg(long)]
+        strict: bool,
+        /// Add intrinsic width/height attributes to local raster images
+        #[arg(long)]
+        img_dimensions: bool,
+        /// Stop at the first file that fails instead of building the rest and reporting all failures
+        #[arg(long)]
+        fail_fast: bool,
+        /// Report which output files would be created, updated or deleted without writing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// Write an index.html listing the pages of every directory that has no index page
+        #[arg(long)]
+        auto_index: bool,
+        /// Write bare page bodies (HTML partials for embedding) plus one output/haystack.css for code highlighting
+        #[arg(long)]
+        fragment: bool,
+        /// After building, request every external http(s) link and report the ones that fail (errors only with --strict)
+        #[arg(long)]
+        check_links: bool,
+        /// Most external links checked at the same time
+        #[arg(long, value_name = "N", default_value_t = 8)]
+        link_concurrency: usize,
+        /// Seconds to wait for each external link before reporting it as timed out
+        #[arg(long, value_name = "SECS", default_value_t = 10)]
+        link_timeout: u64,
+        /// Content hash recorded for each file in the build manifest
+        #[arg(long, value_enum, value_name = "ALGORITHM", default_value_t = HashAlgorithm::Sha256)]
+        hash: HashAlgorithm,
+        /// Don't end generated files (pages, CSS, manifest) with a newline
+        #[arg(long)]
+        no_final_newline: bool,
+        /// Keep running after the build and rebuild files as they change under src/
+        #[arg(long, conflicts_with = "dry_run")]
+        watch: bool,
+        /// Write an Atom feed of the pages that have a date to output/feed.xml
+        #[arg(long, requires = "base_url")]
+        feed: bool,
+        /// Most entries in the feed, newest first
+        #[arg(long, value_name = "N", default_value_t = 20)]
+        feed_limit: usize,
+        /// Also write a gzipped copy (`page.html.gz`) of every HTML, CSS, JS, JSON, XML and SVG output
+        #[arg(long)]
+        gzip_output: bool,
+        #[command(flatten)]
+        render: RenderArgs,
     },
     /// Serve on-demand HTML from src/*.md and src/*.org
     Serve {
         /// Port to listen on
         #[arg(long, default_value_t = 4000)]
         port: u16,
-        /// Light theme name for syntax highlighting (syntect)
-        #[arg(long, value_name = "NAME")]
-        theme_light: Option<String>,
-        /// Dark theme name for syntax highlighting (syntect)
-        #[arg(long, value_name = "NAME")]
-        theme_dark: Option<String>,
+        /// PEM certificate to serve HTTPS with (requires --tls-key)
+        #[arg(long, value_name = "FILE", requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+        /// PEM private key for --tls-cert
+        #[arg(long, value_name = "FILE", requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+        /// Open the site in the default browser once the server is listening
+        #[arg(long)]
+        open: bool,
+        /// Keep up to PAGES rendered pages in memory, re-rendering only when their files change
+        #[arg(long, value_name = "PAGES", num_args = 0..=1, default_missing_value = "256")]
+        cache: Option<usize>,
+        /// Representation of 400/404/500 responses
+        #[arg(long, value_enum, value_name = "FORMAT", default_value_t = ErrorFormat::Html)]
+        error_format: ErrorFormat,
+        /// Never compress responses, on the fly or from precompressed .br/.gz files
+        #[arg(long)]
+        no_compress: bool,
+        /// Reload open pages in the browser when anything under src/ changes
+        #[arg(long)]
+        live_reload: bool,
+        /// Require HTTP Basic authentication with these credentials (the health check stays open)
+        #[arg(long, value_name = "USER:PASS")]
+        auth: Option<String>,
+        /// Allow cross-origin requests from ORIGIN (any origin when given without a value)
+        #[arg(long, value_name = "ORIGIN", num_args = 0..=1, default_missing_value = "*")]
+        cors: Option<String>,
+        #[command(flatten)]
+        render: RenderArgs,
+    },
+    /// Scaffold a new project (src/index.md, output/, theme/head.html, haystack.toml)
+    Init {
+        /// Overwrite files that already exist
+        #[arg(long)]
+        force: bool,
+    },
+    /// Delete everything in output/ (or --out), leaving it empty
+    Clean {
+        /// Directory to empty; must be inside the current directory
+        #[arg(long, value_name = "DIR", default_value = "output")]
+        out: PathBuf,
     },
     /// List available syntax highlighting themes
-    Themes,
+    Themes {
+        /// Print a JSON array with each theme's name, light/dark kind and background color
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print version, build and bundled syntax/theme details (for bug reports)
+    Info {
+        /// Print a JSON object instead
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+/// Rendering flags shared by `build` and `serve`.
+#[derive(Args, Debug)]
+struct RenderArgs {
+    /// Light theme name(s) for syntax highlighting (syntect); comma-separate several to offer readers a picker
+    #[arg(long, value_name = "NAME[,NAME...]")]
+    theme_light: Option<String>,
+    /// Dark theme name(s) for syntax highlighting (syntect); comma-separate several to offer readers a picker
+    #[arg(long, value_name = "NAME[,NAME...]")]
+    theme_dark: Option<String>,
+    /// Directory of extra .sublime-syntax files for code highlighting, merged over the bundled ones
+    #[arg(long, value_name = "DIR")]
+    syntax_dir: Option<PathBuf>,
+    /// .tmTheme file to use as the light theme, in place of --theme-light's first name
+    #[arg(long, value_name = "FILE")]
+    theme_light_file: Option<PathBuf>,
+    /// .tmTheme file to use as the dark theme, in place of --theme-dark's first name
+    #[arg(long, value_name = "FILE")]
+    theme_dark_file: Option<PathBuf>,
+    /// Color mode for visitors who haven't picked one with the theme toggle yet
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = UserTheme::Auto)]
+    default_user_theme: UserTheme,
+    /// Default page language for `<html lang>` (front matter `lang` / `#+LANGUAGE:` override it)
+    #[arg(long, value_name = "LANG")]
+    lang: Option<String>,
+    /// Inline every local `.svg` image as markup (append `#inline` to a single image URL instead)
+    #[arg(long)]
+    inline_svg: bool,
+    /// Visible anchor link added to headings (ids are always added)
+    #[arg(long, value_enum, value_name = "STYLE", default_value_t = AnchorStyle::None)]
+    anchor_style: AnchorStyle,
+    /// Embed the page's front matter as `<script type="application/json" id="haystack-frontmatter">`
+    #[arg(long)]
+    embed_frontmatter: bool,
+    /// Embed the page's heading tree as `<script type="application/json" id="haystack-outline">` (markdown pages)
+    #[arg(long)]
+    outline: bool,
+    /// Strip leading and trailing blank lines from code blocks
+    #[arg(long)]
+    trim_code: bool,
+    /// Collapse runs of 3+ blank lines inside code blocks to a single blank line
+    #[arg(long)]
+    collapse_blank_lines: bool,
+    /// Expand tabs in code blocks to the next multiple of N columns (0 keeps tabs)
+    #[arg(long, value_name = "N", default_value_t = 4)]
+    tab_width: usize,
+    /// Highlight fenced code blocks without a language as LANG (indented blocks stay plain text)
+    #[arg(long, value_name = "LANG")]
+    default_lang: Option<String>,
+    /// Link each page to its `.md`/`.org` source (build also copies the source into output/)
+    #[arg(long)]
+    include_source: bool,
+    /// Turn `// @anchor ID` comments in code blocks into linkable ids on their lines
+    #[arg(long)]
+    code_anchors: bool,
+    /// Give code blocks the syntax theme's own background instead of the site's code background
+    #[arg(long)]
+    code_theme_bg: bool,
+    /// Fold code blocks longer than N lines into a `<details>` showing a short preview
+    #[arg(long, value_name = "N")]
+    collapse_code: Option<usize>,
+    /// Path the site is deployed under (e.g. `/docs`); prefixed to root-relative link and image URLs
+    #[arg(long, value_name = "PATH")]
+    base_path: Option<String>,
+    /// Absolute URL of the deployed site (e.g. `https://example.com/docs`), for Open Graph tags and build's sitemap.xml
+    #[arg(long, value_name = "URL")]
+    base_url: Option<String>,
+    /// Maximum length of the auto-generated page description, in characters
+    #[arg(long, value_name = "N", default_value_t = 160)]
+    excerpt_length: usize,
+    /// Let long code lines scroll horizontally instead of wrapping (`wrap` in an info string opts a block back in)
+    #[arg(long)]
+    no_wrap_code: bool,
+    /// Don't wrap markdown tables in a horizontally scrolling `<div class="table-wrap">`
+    #[arg(long)]
+    no_table_wrap: bool,
+    /// Remove `<!-- comments -->` from page bodies (IE conditional comments are kept)
+    #[arg(long)]
+    strip_comments: bool,
+    /// Wrap standalone images with alt text in numbered `<figure>`s ("Figure N: alt")
+    #[arg(long)]
+    figures: bool,
+    /// Make task list checkboxes clickable, remembering their state in the reader's browser
+    #[arg(long)]
+    interactive_tasks: bool,
+    /// Open http(s) links to other hosts than --base-url's in a new tab (`target="_blank" rel="noopener noreferrer"`)
+    #[arg(long)]
+    external_links_new_tab: bool,
+    /// Mark those links with a ↗ after the text
+    #[arg(long, requires = "external_links_new_tab")]
+    external_link_icon: bool,
+    /// Convert `$...$` and `$$...$$` math in markdown to MathML at render time (no script needed)
+    #[arg(long)]
+    math_mathml: bool,
+    /// Keep `$...$` and `$$...$$` math in markdown as TeX and typeset it in the browser with KaTeX (with --math-mathml, only math it can't convert)
+    #[arg(long)]
+    math: bool,
+    /// Number the lines of code blocks (the numbers aren't copied with the code)
+    #[arg(long)]
+    line_numbers: bool,
+    /// Render ```mermaid code blocks (and org `mermaid` src blocks) as diagrams with mermaid.js
+    #[arg(long)]
+    mermaid: bool,
+    /// Add a sidebar listing every page, grouped by directory, with the current page highlighted
+    #[arg(long)]
+    sidebar: bool,
+    /// Descend into symlinked directories under src/ (symlink loops are skipped with a warning)
+    #[arg(long)]
+    follow_symlinks: bool,
+    /// Add a table of contents of the page's headings after its title (markdown pages)
+    #[arg(long)]
+    toc: bool,
+    /// Shallowest heading level listed in the table of contents
+    #[arg(long, value_name = "N", default_value_t = 2, value_parser = clap::value_parser!(u8).range(1..=6))]
+    toc_min_level: u8,
+    /// Deepest heading level listed in the table of contents
+    #[arg(long, value_name = "N", default_value_t = 3, value_parser = clap::value_parser!(u8).range(1..=6))]
+    toc_max_level: u8,
+    /// Fewest listed headings a page needs to get a table of contents
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    toc_min_headings: usize,
+    /// Put block elements of generated pages on their own indented lines (`<pre>` contents are left alone)
+    #[arg(long)]
+    pretty_html: bool,
+    /// Line endings of generated pages
+    #[arg(long, value_enum, value_name = "STYLE", default_value_t = LineEnding::Lf)]
+    line_endings: LineEnding,
+}
+
+impl RenderArgs {
+    fn theme(&self) -> ThemeConfig {
+        ThemeConfig {
+            light: self.theme_light.clone(),
+            dark: self.theme_dark.clone(),
+            light_file: self.theme_light_file.as_deref().and_then(|path| load_theme_file(path, "light")),
+            dark_file: self.theme_dark_file.as_deref().and_then(|path| load_theme_file(path, "dark")),
+        }
+    }
+
+    fn options(&self, config: &Config) -> Result<RenderOptions> {
+        if let Some(dir) = &self.syntax_dir {
+            if !dir.is_dir() {
+                return Err(anyhow!("--syntax-dir {}: not a directory", dir.display()));
+            }
+            // Before anything touches SYNTAX_SET, the [languages] check below included
+            let _ = SYNTAX_DIR.set(dir.clone());
+        }
+        let mut languages = BTreeMap::new();
+        for (alias, target) in &config.languages {
+            let syntax = SYNTAX_SET.find_syntax_by_name(target).or_else(|| SYNTAX_SET.find_syntax_by_token(target));
+            match syntax {
+                Some(syntax) => languages.insert(alias.to_lowercase(), syntax.name.clone()),
+                None => return Err(anyhow!("[languages] {} = {:?} in {}: no such syntax", alias, target, CONFIG_FILE)),
+            };
+        }
+        let mut code_themes = BTreeMap::new();
+        for (lang, name) in &config.code_themes {
+            if resolve_theme(Some(name)).is_none() {
+                return Err(anyhow!("[code_themes] {} = {:?} in {}: no such theme", lang, name, CONFIG_FILE));
+            }
+            code_themes.insert(lang.to_lowercase(), name.trim().to_string());
+        }
+        if let Some(url) = &self.base_url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(anyhow!("--base-url {:?} must be an absolute http(s) URL", url));
+            }
+        }
+        if self.toc_min_level > self.toc_max_level {
+            return Err(anyhow!(
+                "--toc-min-level {} is deeper than --toc-max-level {}",
+                self.toc_min_level,
+                self.toc_max_level
+            ));
+        }
+        if let Some(lang) = &self.default_lang {
+            if resolve_syntax(lang, &languages).is_none() {
+                return Err(anyhow!("unknown --default-lang {:?}: no syntax highlighting for it", lang));
+            }
+        }
+        Ok(RenderOptions {
+            lang: self.lang.clone(),
+            inline_svg: self.inline_svg,
+            anchor_style: self.anchor_style,
+            default_user_theme: self.default_user_theme,
+            embed_frontmatter: self.embed_frontmatter,
+            outline: self.outline,
+            trim_code: self.trim_code,
+            collapse_blank_lines: self.collapse_blank_lines,
+            tab_width: self.tab_width,
+            collapse_code: self.collapse_code,
+            code_anchors: self.code_anchors,
+            code_theme_bg: self.code_theme_bg,
+            include_source: self.include_source,
+            default_lang: self.default_lang.clone(),
+            base_path: self.base_path.as_deref().and_then(normalize_base_path),
+            base_url: self.base_url.as_deref().map(|u| u.trim_end_matches('/').to_string()),
+            excerpt_length: self.excerpt_length,
+            excerpt_separator: match config.excerpt_separator.as_deref() {
+                None => Some(DEFAULT_EXCERPT_SEPARATOR.to_string()),
+                Some("") => None,
+                Some(separator) => Some(separator.to_string()),
+            },
+            no_wrap_code: self.no_wrap_code,
+            no_table_wrap: self.no_table_wrap,
+            strip_comments: self.strip_comments,
+            figures: self.figures,
+            interactive_tasks: self.interactive_tasks,
+            external_links_new_tab: self.external_links_new_tab,
+            external_link_icon: self.external_link_icon,
+            math_mathml: self.math_mathml,
+            math: self.math,
+            mermaid: self.mermaid,
+            line_numbers: self.line_numbers,
+            sidebar: self.sidebar,
+            follow_symlinks: self.follow_symlinks,
+            toc: self.toc.then_some(self.toc_min_level..=self.toc_max_level),
+            toc_min_headings: self.toc_min_headings,
+            pretty_html: self.pretty_html,
+            line_endings: self.line_endings,
+            mdx: config.mdx.clone(),
+            languages,
+            code_themes,
+            image_sizes: None,
+            live_reload: false,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct ThemeConfig {
+    light: Option<String>,
+    dark: Option<String>,
+    /// Themes loaded from `--theme-light-file`/`--theme-dark-file`; they win
+    /// over the first named theme
+    light_file: Option<Theme>,
+    dark_file: Option<Theme>,
+}
+
+impl ThemeConfig {
+    /// Light theme names in order; the first is the page default.
+    fn light_names(&self) -> Vec<&str> {
+        split_theme_list(self.light.as_deref())
+    }
+
+    /// Dark theme names in order; the first is the page default.
+    fn dark_names(&self) -> Vec<&str> {
+        split_theme_list(self.dark.as_deref())
+    }
+
+    /// Whether readers get a code theme picker (more than one light or dark theme given).
+    fn has_picker(&self) -> bool {
+        self.light_names().len() > 1 || self.dark_names().len() > 1
+    }
+}
+
+fn split_theme_list(list: Option<&str>) -> Vec<&str> {
+    list.map(|l| l.split(',').map(str::trim).filter(|n| !n.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, Default)]
+struct RenderOptions {
+    lang: Option<String>,
+    inline_svg: bool,
+    anchor_style: AnchorStyle,
+    default_user_theme: UserTheme,
+    embed_frontmatter: bool,
+    outline: bool,
+    trim_code: bool,
+    collapse_blank_lines: bool,
+    tab_width: usize,
+    collapse_code: Option<usize>,
+    code_anchors: bool,
+    code_theme_bg: bool,
+    include_source: bool,
+    default_lang: Option<String>,
+    /// Normalized to a leading and no trailing `/`; `None` when deploying at the root
+    base_path: Option<String>,
+    /// Without a trailing `/`
+    base_url: Option<String>,
+    excerpt_length: usize,
+    /// Marker whose preceding text is the whole excerpt
+    excerpt_separator: Option<String>,
+    no_wrap_code: bool,
+    no_table_wrap: bool,
+    strip_comments: bool,
+    figures: bool,
+    interactive_tasks: bool,
+    external_links_new_tab: bool,
+    external_link_icon: bool,
+    math_mathml: bool,
+    /// Math left as TeX for KaTeX (`--math`)
+    math: bool,
+    mermaid: bool,
+    line_numbers: bool,
+    sidebar: bool,
+    follow_symlinks: bool,
+    /// Heading levels listed in the `--toc`; `None` without one
+    toc: Option<std::ops::RangeInclusive<u8>>,
+    toc_min_headings: usize,
+    pretty_html: bool,
+    line_endings: LineEnding,
+    mdx: MdxConfig,
+    /// Lowercased `[languages]` aliases → syntax names
+    languages: BTreeMap<String, String>,
+    /// Lowercased `[code_themes]` languages → theme names
+    code_themes: BTreeMap<String, String>,
+    /// Set by `build --img-dimensions`
+    image_sizes: Option<ImageSizes>,
+    /// Set by `serve --live-reload`: pages load the reload script
+    live_reload: bool,
+}
+
+/// `(width, height)` in pixels.
+type ImageSize = (u32, u32);
+
+/// Intrinsic sizes of local raster images, looked up once per path for the
+/// duration of a build.
+#[derive(Debug, Clone)]
+struct ImageSizes {
+    src_dir: PathBuf,
+    cache: Rc<RefCell<HashMap<PathBuf, Option<ImageSize>>>>,
+}
+
+impl ImageSizes {
+    fn new(src_dir: &Path) -> Self {
+        ImageSizes { src_dir: src_dir.to_path_buf(), cache: Rc::default() }
+    }
+
+    /// Width and height of the image file, or `None` if it can't be read.
+    fn get(&self, path: &Path) -> Option<ImageSize> {
+        if let Some(size) = self.cache.borrow().get(path) {
+            return *size;
+        }
+        let size = image::image_dimensions(path)
+            .map_err(|e| eprintln!("[haystack] cannot read size of {}: {}", path.display(), e))
+            .ok();
+        self.cache.borrow_mut().insert(path.to_path_buf(), size);
+        size
+    }
+
+    /// Adds `width`/`height` to `<img>` tags pointing at local raster images.
+    /// Relative URLs resolve against the page's directory, root-relative ones
+    /// (with any base path removed) against the source root. Remote images,
+    /// SVGs and tags that already carry a size are left alone.
+    fn annotate(&self, html: &str, source: &Path, base_path: Option<&str>) -> String {
+        static RE_IMG: Lazy<Regex> = Lazy::new(|| Regex::new(r"<img\b[^>]*>").unwrap());
+        static RE_SRC: Lazy<Regex> = Lazy::new(|| Regex::new(r#"\ssrc="([^"]*)""#).unwrap());
+        RE_IMG
+            .replace_all(html, |caps: &regex::Captures| {
+                let tag = &caps[0];
+                if tag.contains(" width=") || tag.contains(" height=") {
+                    return tag.to_string();
+                }
+                let Some(src) = RE_SRC.captures(tag) else { return tag.to_string() };
+                let url = src[1].split(['?', '#']).next().unwrap_or("");
+                if url.contains("://") || url.starts_with("//") || url.starts_with("data:") || url.to_ascii_lowercase().ends_with(".svg") {
+                    return tag.to_string();
+                }
+                let file = match url.strip_prefix('/') {
+                    Some(root_rel) => {
+                        let root_rel = base_path
+                            .and_then(|base| url.strip_prefix(base))
+                            .and_then(|rest| rest.strip_prefix('/'))
+                            .unwrap_or(root_rel);
+                        self.src_dir.join(root_rel)
+                    }
+                    None => source.parent().unwrap_or(Path::new("")).join(url),
+                };
+                match self.get(&file) {
+                    Some((w, h)) => {
+                        let end = src.get(0).unwrap().end();
+                        format!("{} width=\"{}\" height=\"{}\"{}", &tag[..end], w, h, &tag[end..])
+                    }
+                    None => tag.to_string(),
+                }
+            })
+            .into_owned()
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum AnchorStyle {
+    /// Only the heading id, no visible link
+    #[default]
+    None,
+    /// A `#` link before the heading text
+    Hash,
+    /// A link icon after the heading text, shown on hover
+    Icon,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum LineEnding {
+    #[default]
+    Lf,
+    Crlf,
+}
+
+/// Page color mode, as stored in `<html data-theme>` and by the theme toggle.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum UserTheme {
+    Light,
+    Dark,
+    /// Follow the system's `prefers-color-scheme`
+    #[default]
+    Auto,
+}
+
+impl UserTheme {
+    fn name(self) -> &'static str {
+        match self {
+            UserTheme::Light => "light",
+            UserTheme::Dark => "dark",
+            UserTheme::Auto => "auto",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct BuildOptions {
+    manifest: bool,
+    single_page: bool,
+    strict: bool,
+    img_dimensions: bool,
+    fail_fast: bool,
+    dry_run: bool,
+    auto_index: bool,
+    /// Page bodies only, without `wrap_html_page`'s document and styles
+    fragment: bool,
+    /// Digest of each output in the manifest
+    hash: HashAlgorithm,
+    /// End every generated file with exactly one newline
+    final_newline: bool,
+    /// Request external links once the build is done
+    check_links: Option<LinkCheck>,
+    /// Write the Atom feed, with at most this many entries
+    feed: Option<usize>,
+    /// Write a `.gz` sibling next to each text output
+    gzip_output: bool,
+}
+
+#[derive(ValueEnum, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum HashAlgorithm {
+    /// SHA-256, 64 hex digits
+    #[default]
+    Sha256,
+    /// XXH3, 64-bit and not cryptographic; 16 hex digits
+    Xxhash,
+    /// BLAKE3, 64 hex digits
+    Blake3,
+}
+
+impl HashAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Xxhash => "xxhash",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+}
+
+/// Lowercase hex digest of `bytes`. Every content hash goes through here so
+/// the algorithm is chosen in one place.
+fn content_hash(algorithm: HashAlgorithm, bytes: &[u8]) -> String {
+    match algorithm {
+        HashAlgorithm::Sha256 => format!("{:x}", Sha256::digest(bytes)),
+        HashAlgorithm::Xxhash => format!("{:016x}", xxhash_rust::xxh3::xxh3_64(bytes)),
+        HashAlgorithm::Blake3 => blake3::hash(bytes).to_hex().to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LinkCheck {
+    concurrency: usize,
+    timeout: Duration,
+}
+
+/// Settings only `serve` uses.
+#[derive(Debug, Clone)]
+struct ServeOptions {
+    port: u16,
+    /// Certificate and private key files; serve HTTPS when set
+    tls: Option<(PathBuf, PathBuf)>,
+    /// Launch the browser after binding
+    open: bool,
+    /// Capacity of the rendered-page cache; `None` renders every request
+    cache: Option<usize>,
+    error_format: ErrorFormat,
+    /// Negotiate `Content-Encoding` with clients (off with `--no-compress`)
+    compress: bool,
+    /// Base64 of `--auth`'s `user:pass`, as sent in `Authorization: Basic`
+    auth: Option<String>,
+    /// `Access-Control-Allow-Origin` value for `--cors`
+    cors: Option<String>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum ErrorFormat {
+    /// A page in the site's layout
+    #[default]
+    Html,
+    /// `text/plain`: status line, then the failing file and error chain
+    Text,
+    /// `application/json`: `{"status", "error", "path", "message"}`
+    Json,
+}
+
+const CONFIG_FILE: &str = "haystack.toml";
+
+/// Optional project settings read from `haystack.toml` in the working directory.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    /// Marker ending a page's excerpt; `None` means `<!--more-->`, `""` turns it off
+    excerpt_separator: Option<String>,
+    mdx: MdxConfig,
+    /// `[languages]`: code block language → syntax name or token, checked before syntect's own tokens
+    languages: BTreeMap<String, String>,
+    /// `[code_themes]`: code block language → theme used in every color mode
+    code_themes: BTreeMap<String, String>,
+}
+
+/// `[mdx]`: how JSX-style component tags (`<Note>...</Note>`) in markdown are rewritten.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct MdxConfig {
+    /// Component name → replacement element as `tag.class.class` (e.g. `div.note`)
+    components: BTreeMap<String, String>,
+    unmapped: UnmappedComponents,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum UnmappedComponents {
+    /// Leave the tag in the output as-is
+    #[default]
+    Keep,
+    /// Drop the tag but keep its content
+    Strip,
+    /// Leave the tag and print a warning
+    Warn,
+}
+
+/// Contents of `output/.haystack-manifest.json`.
+#[derive(Debug, Serialize)]
+struct Manifest {
+    /// Algorithm of every entry's digest
+    hash: HashAlgorithm,
+    files: Vec<ManifestEntry>,
+}
+
+/// One file written by `build_all`, as recorded in the build manifest.
+#[derive(Debug, Serialize)]
+struct ManifestEntry {
+    path: String,
+    source: String,
+    kind: OutputKind,
+    size: u64,
+    /// One key named after the algorithm (`"sha256": "..."`)
+    #[serde(flatten)]
+    digest: BTreeMap<&'static str, String>,
+    /// Other source files the output was built from (included snippets,
+    /// inlined SVGs, the page template), so they can trigger a rebuild
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    dependencies: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum OutputKind {
+    Converted,
+    Copied,
+    /// Site-wide files such as the single-page bundle
+    Generated,
+}
+
+const MANIFEST_FILE: &str = ".haystack-manifest.json";
+
+/// Metadata from a leading `---` YAML block in a markdown file.
+#[derive(Debug, Clone, Default)]
+struct FrontMatter {
+    /// Overrides the title taken from the first heading
+    title: Option<String>,
+    description: Option<String>,
+    /// Publication date, as written
+    date: Option<String>,
+    /// Draft pages are left out of `build` (`serve` still renders them)
+    draft: bool,
+    lang: Option<String>,
+    order: Option<i64>,
+    /// Preview image for link sharing (`og:image`)
+    image: Option<String>,
+    /// `css`/`js`: a string or a list, each entry a path/URL or an inline block
+    css: Vec<String>,
+    js: Vec<String>,
+    /// Every key of the block, for `--embed-frontmatter`
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Per-page values gathered during conversion and consumed by `wrap_html_page`.
+#[derive(Debug, Clone, Default)]
+struct PageMeta {
+    title: Option<String>,
+    description: Option<String>,
+    /// Front matter `date` (org: `#+DATE:`), for `<meta name="date">` and the feed
+    date: Option<String>,
+    lang: String,
+    /// Position in the `--single-page` bundle and among sibling pages (front
+    /// matter `order`/`weight`, org `#+ORDER:`/`#+WEIGHT:`)
+    order: Option<i64>,
+    /// Page has `--interactive-tasks` checkboxes that need the persistence script
+    has_tasks: bool,
+    /// Page has `--math` formulas that need KaTeX
+    has_math: bool,
+    /// Page has `--mermaid` diagrams that need mermaid.js
+    has_mermaid: bool,
+    front_matter: serde_json::Map<String, serde_json::Value>,
+    /// File name of the `.md`/`.org` source, for the `--include-source` link
+    source_name: Option<String>,
+    /// Files whose contents went into the page (`file:` code blocks, inlined SVGs)
+    includes: Vec<PathBuf>,
+    /// Previous/next links among ordered sibling pages
+    nav: Option<PageNav>,
+    /// Page-specific stylesheets and scripts from front matter `css`/`js`
+    css: Vec<PageAsset>,
+    js: Vec<PageAsset>,
+    /// `--sidebar` markup for this page
+    sidebar: Option<String>,
+    /// Front matter `image`, else the first raster image on the page, as written
+    image: Option<String>,
+    /// Output path relative to the site root (`guide/intro.html`), for resolving `image`
+    url_path: Option<String>,
+    /// Every heading with its emitted id, in document order (markdown pages)
+    headings: Vec<TocEntry>,
+    /// Headings more than one level deeper than the one before (already warned about)
+    heading_skips: usize,
+    /// Code blocks in a language there's no highlighting for (already warned about)
+    unknown_languages: usize,
+    /// Images with no alt text (already warned about; markdown pages)
+    empty_alts: usize,
+}
+
+/// A front matter `css`/`js` entry: a URL to link, or code to embed (any entry
+/// spanning several lines).
+#[derive(Debug, Clone)]
+enum PageAsset {
+    Url(String),
+    Inline(String),
+}
+
+impl PageAsset {
+    fn stylesheet_html(&self) -> String {
+        match self {
+            PageAsset::Url(url) => format!("<link rel=\"stylesheet\" href=\"{}\">\n", escape_html(url)),
+            PageAsset::Inline(css) => format!("<style>\n{}\n</style>\n", css),
+        }
+    }
+
+    fn script_html(&self) -> String {
+        match self {
+            PageAsset::Url(url) => format!("<script src=\"{}\"></script>\n", escape_html(url)),
+            PageAsset::Inline(js) => format!("<script>\n{}\n</script>\n", js),
+        }
+    }
+}
+
+/// Turns front matter `css`/`js` entries into assets. Relative paths resolve
+/// against the page (its output sits at the same place relative to the copied
+/// static files); a missing file is only a warning.
+fn page_assets(entries: &[String], key: &str, source: &Path, base_path: Option<&str>) -> Vec<PageAsset> {
+    entries
+        .iter()
+        .map(|entry| {
+            if entry.contains('\n') {
+                return PageAsset::Inline(entry.clone());
+            }
+            let url = entry.trim();
+            let is_external = url.contains("://") || url.starts_with("//") || url.starts_with("data:");
+            if url.starts_with('/') && !url.starts_with("//") {
+                return PageAsset::Url(format!("{}{}", base_path.unwrap_or(""), url));
+            }
+            if !is_external {
+                let file = source.parent().unwrap_or(Path::new("")).join(url);
+                if !file.is_file() {
+                    eprintln!("[haystack] {}: {} file {} not found", source.display(), key, file.display());
+                }
+            }
+            PageAsset::Url(url.to_string())
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Default)]
+struct PageNav {
+    prev: Option<NavLink>,
+    next: Option<NavLink>,
+}
+
+/// A link to a sibling page: its output file name and title.
+#[derive(Debug, Clone)]
+struct NavLink {
+    href: String,
+    title: String,
+}
+
+/// A page body and its metadata, before `wrap_html_page` adds the layout.
+#[derive(Debug, Clone)]
+struct ConvertedPage {
+    body: String,
+    meta: PageMeta,
+}
+
+/// Exit code for errors: a build that failed, or any other command error.
+const EXIT_FAILURE: u8 = 1;
+/// Exit code when `--strict` turned warnings into a failure. (Clap exits with
+/// 2 on usage errors.)
+const EXIT_STRICT: u8 = 3;
+
+/// Warnings that `--strict` escalated; reported with [`EXIT_STRICT`].
+#[derive(Debug)]
+struct StrictWarnings(String);
+
+impl std::fmt::Display for StrictWarnings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (--strict)", self.0)
+    }
+}
+
+impl std::error::Error for StrictWarnings {}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            if e.chain().any(|cause| cause.is::<StrictWarnings>()) {
+                ExitCode::from(EXIT_STRICT)
+            } else {
+                ExitCode::from(EXIT_FAILURE)
+            }
+        }
+    }
+}
+
+fn run() -> Result<()> {
+    let cli = Cli::parse();
+    let config = load_config(Path::new(CONFIG_FILE))?;
+
+    match cli.command {
+        Commands::Build {
+            no_manifest,
+            single_page,
+            strict,
+            img_dimensions,
+            fail_fast,
+            dry_run,
+            auto_index,
+            fragment,
+            check_links,
+            link_concurrency,
+            link_timeout,
+            hash,
+            no_final_newline,
+            watch,
+            feed,
+            feed_limit,
+            gzip_output,
+            render,
+        } => {
+            let src = Path::new("src");
+            let out = Path::new("output");
+            let build = BuildOptions {
+                manifest: !no_manifest,
+                single_page,
+                strict,
+                img_dimensions,
+                fail_fast,
+                dry_run,
+                auto_index,
+                fragment,
+                hash,
+                final_newline: !no_final_newline,
+                feed: feed.then_some(feed_limit),
+                gzip_output,
+                check_links: check_links.then_some(LinkCheck {
+                    concurrency: link_concurrency.max(1),
+                    timeout: Duration::from_secs(link_timeout),
+                }),
+            };
+            if watch {
+                watch_build(src, out, &render.theme(), &render.options(&config)?, &build)?;
+            } else {
+                build_all(src, out, &render.theme(), &render.options(&config)?, &build)?;
+            }
+        }
+        Commands::Serve { port, tls_cert, tls_key, open, cache, error_format, no_compress, live_reload, auth, cors, render } => {
+            let src = Path::new("src");
+            let auth = match auth.as_deref().map(|a| a.split_once(':')) {
+                Some(Some((user, _))) if !user.is_empty() => auth.as_deref().map(|a| base64_encode(a.as_bytes())),
+                Some(_) => return Err(anyhow!("--auth must be USER:PASS with a non-empty user")),
+                None => None,
+            };
+            let server = ServeOptions {
+                port,
+                tls: tls_cert.zip(tls_key),
+                open,
+                cache,
+                error_format,
+                compress: !no_compress,
+                auth,
+                cors,
+            };
+            let opts = RenderOptions { live_reload, ..render.options(&config)? };
+            serve(src, &render.theme(), &opts, &server)?;
+        }
+        Commands::Init { force } => {
+            init_project(Path::new("."), force)?;
+        }
+        Commands::Clean { out } => {
+            clean_output(Path::new("."), &out)?;
+        }
+        Commands::Themes { json } => {
+            if json {
+                println!("{}", serde_json::to_string_pretty(&theme_infos())?);
+            } else {
+                list_themes();
+            }
+        }
+        Commands::Info { json } => {
+            let info = build_info();
+            if json {
+                println!("{}", serde_json::to_string_pretty(&info)?);
+            } else {
+                print_build_info(&info);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+const INIT_INDEX_MD: &str = "---
+description: A site built with haystack.
+---
+
+# Hello, haystack
+
+Edit `src/index.md`, then run `haystack serve` to preview or `haystack build` to write `output/`.
+";
+
+const INIT_HEAD_HTML: &str = "<!-- Injected into the <head> of every page. -->
+<!-- <link rel=\"icon\" href=\"/favicon.ico\"> -->
+";
+
+const INIT_CONFIG: &str = "# haystack project settings. Every key is optional.
+
+# Marker that ends a page's excerpt (\"\" disables it).
+# excerpt_separator = \"<!--more-->\"
+
+# Map MDX-style component tags to plain HTML elements.
+# [mdx]
+# unmapped = \"keep\"   # \"keep\", \"strip\" or \"warn\"
+#
+# [mdx.components]
+# Note = \"div.note\"
+";
+
+/// Empties `out` by removing and recreating it. Refuses anything that isn't
+/// strictly inside `root` (so never `/` or the project itself) and anything
+/// holding the `src/` folder.
+fn clean_output(root: &Path, out: &Path) -> Result<()> {
+    let root = fs::canonicalize(root).with_context(|| format!("resolving {}", root.display()))?;
+    let target = root.join(out);
+    if !target.exists() {
+        fs::create_dir_all(&target).with_context(|| format!("creating {}", target.display()))?;
+        println!("Removed 0 file(s); created {}", out.display());
+        return Ok(());
+    }
+    let target = fs::canonicalize(&target).with_context(|| format!("resolving {}", out.display()))?;
+    if target == root || !target.starts_with(&root) || target.parent().is_none() {
+        return Err(anyhow!("refusing to clean {}: not inside {}", target.display(), root.display()));
+    }
+    if root.join("src").starts_with(&target) {
+        return Err(anyhow!("refusing to clean {}: it holds the src folder", target.display()));
+    }
+    if !target.is_dir() {
+        return Err(anyhow!("refusing to clean {}: not a directory", target.display()));
+    }
+    let files = WalkDir::new(&target).into_iter().filter_map(|e| e.ok()).filter(|e| !e.file_type().is_dir()).count();
+    fs::remove_dir_all(&target).with_context(|| format!("removing {}", target.display()))?;
+    fs::create_dir_all(&target).with_context(|| format!("creating {}", target.display()))?;
+    println!("Removed {} file(s) from {}", files, out.display());
+    Ok(())
+}
+
+/// Write the starter layout into `root`. Existing files are left alone (and
+/// reported) unless `force` is set.
+fn init_project(root: &Path, force: bool) -> Result<()> {
+    let files = [
+        ("src/index.md", INIT_INDEX_MD),
+        ("theme/head.html", INIT_HEAD_HTML),
+        (CONFIG_FILE, INIT_CONFIG),
+    ];
+    let existing: Vec<&str> = files
+        .iter()
+        .map(|(rel, _)| *rel)
+        .filter(|rel| root.join(rel).exists())
+        .collect();
+    if !force && !existing.is_empty() {
+        return Err(anyhow!(
+            "refusing to overwrite existing files (use --force): {}",
+            existing.join(", ")
+        ));
+    }
+    for (rel, contents) in files {
+        let path = root.join(rel);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("creating {}", parent.display()))?;
+        }
+        fs::write(&path, contents).with_context(|| format!("writing {}", path.display()))?;
+        println!("Created {}", rel);
+    }
+    let out = root.join("output");
+    fs::create_dir_all(&out).with_context(|| format!("creating {}", out.display()))?;
+    println!("Created output/");
+    Ok(())
+}
+
+fn load_config(path: &Path) -> Result<Config> {
+    match fs::read_to_string(path) {
+        Ok(s) => toml::from_str(&s).with_context(|| format!("parsing {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+    }
+}
+
+fn build_all(
+    src_dir: &Path,
+    out_dir: &Path,
+    theme: &ThemeConfig,
+    opts: &RenderOptions,
+    build: &BuildOptions,
+) -> Result<()> {
+    build_site(src_dir, out_dir, theme, opts, build, &mut HashMap::new())
+}
+
+/// Page → files it includes (`file:` snippets, inlined SVGs), lexically
+/// normalized so they compare equal to the paths `--watch` sees.
+type IncludeGraph = HashMap<PathBuf, Vec<PathBuf>>;
+
+/// `build_all`, recording every converted page's includes in `graph`, even
+/// when some pages fail.
+fn build_site(
+    src_dir: &Path,
+    out_dir: &Path,
+    theme: &ThemeConfig,
+    opts: &RenderOptions,
+    build: &BuildOptions,
+    graph: &mut IncludeGraph,
+) -> Result<()> {
+    graph.clear();
+    if !src_dir.exists() {
+        return Err(anyhow!("src folder not found: {}", src_dir.display()));
+    }
+    if !build.dry_run {
+        fs::create_dir_all(out_dir).with_context(|| format!("creating {}", out_dir.display()))?;
+    }
+    let mut sink = OutputSink::new(build);
+    let mut opts = opts.clone();
+    if build.img_dimensions {
+        opts.image_sizes = Some(ImageSizes::new(src_dir));
+    }
+    let opts = &opts;
+    let mut manifest: Vec<ManifestEntry> = Vec::new();
+    let mut bundle: Vec<(PathBuf, ConvertedPage)> = Vec::new();
+    // Source path and title of every converted page, for --auto-index
+    let mut listing: Vec<(PathBuf, Option<String>)> = Vec::new();
+    // Output path and source modification time of every converted page
+    let mut sitemap: Vec<(String, Option<SystemTime>)> = Vec::new();
+    let mut feed: Vec<FeedEntry> = Vec::new();
+
+    let (drafts, sources): (Vec<PathBuf>, Vec<PathBuf>) =
+        collect_sources(src_dir, build.strict, opts.follow_symlinks)?.into_iter().partition(|p| is_draft(p));
+    if !build.dry_run {
+        for draft in &drafts {
+            println!("Skipped draft {}", draft.display());
+        }
+    }
+    let navs = page_navs(&sources);
+    let tree = opts.sidebar.then(|| site_tree(src_dir, &sources));
+    // External URL -> pages linking to it, for --check-links
+    let mut external_links: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    let mut heading_skips = 0;
+    let mut unknown_languages = 0;
+    let mut empty_alts = 0;
+
+    let mut build_file = |path: &Path| -> Result<()> {
+        match path.extension().and_then(|s| s.to_str()) {
+            Some("md") | Some("org") => {
+                let rel = path.strip_prefix(src_dir).unwrap();
+                // Keep subdirectories structure
+                let out_path = out_dir.join(output_rel_path(rel));
+
+                let (page, html) = render_source_page(src_dir, path, theme, opts, build, &navs, tree.as_ref())?;
+                heading_skips += page.meta.heading_skips;
+                unknown_languages += page.meta.unknown_languages;
+                empty_alts += page.meta.empty_alts;
+                listing.push((rel.to_path_buf(), page.meta.title.clone()));
+                sitemap.push((slash_path(&output_rel_path(rel)), fs::metadata(path).and_then(|m| m.modified()).ok()));
+                if let (Some(_), Some(date)) = (build.feed, page.meta.date.as_deref()) {
+                    match feed_timestamp(date) {
+                        Some(updated) => feed.push(FeedEntry {
+                            title: page.meta.title.clone().unwrap_or_else(|| slash_path(rel)),
+                            path: slash_path(&output_rel_path(rel)),
+                            updated,
+                            summary: page.meta.description.clone(),
+                        }),
+                        None => eprintln!("[haystack] {}: date {:?} isn't YYYY-MM-DD or RFC 3339, left out of the feed", path.display(), date),
+                    }
+                }
+                if build.check_links.is_some() {
+                    for url in external_urls(&page.body) {
+                        external_links.entry(url).or_default().push(path.to_path_buf());
+                    }
+                }
+                let page_includes = page.meta.includes.clone();
+                graph.insert(path.to_path_buf(), page_includes.iter().map(|p| normalize_path(p)).collect());
+                if build.single_page {
+                    bundle.push((rel.to_path_buf(), page));
+                }
+                let bytes = sink.write_text(&out_path, &html)?;
+                if build.manifest {
+                    let mut entry = manifest_entry(out_dir, &out_path, rel, OutputKind::Converted, &bytes, build.hash);
+                    let template = template_path(src_dir, path).filter(|_| !build.fragment);
+                    let dir_configs = if build.fragment { Vec::new() } else { dir_config_paths(src_dir, path) };
+                    let mut deps: Vec<String> = page_includes
+                        .iter()
+                        .chain(template.as_ref())
+                        .chain(&dir_configs)
+                        .map(|dep| slash_path(dep.strip_prefix(src_dir).unwrap_or(dep)))
+                        .collect();
+                    deps.sort();
+                    deps.dedup();
+                    entry.dependencies = deps;
+                    manifest.push(entry);
+                }
+                if opts.include_source {
+                    let source_out = out_dir.join(rel);
+                    sink.copy(path, &source_out)?;
+                    if build.manifest {
+                        let bytes = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+                        manifest.push(manifest_entry(out_dir, &source_out, rel, OutputKind::Copied, &bytes, build.hash));
+                    }
+                }
+                if !build.dry_run {
+                    println!(
+                        "Built {} -> {}",
+                        path.display(),
+                        out_path.display()
+                    );
+                }
+            }
+            _ => {
+                // Copy static files as-is
+                let rel = path.strip_prefix(src_dir).unwrap();
+                let mut out_path = out_dir.to_path_buf();
+                out_path.push(rel);
+                sink.copy(path, &out_path)?;
+                if build.manifest {
+                    let bytes = fs::read(path)
+                        .with_context(|| format!("reading {}", path.display()))?;
+                    manifest.push(manifest_entry(out_dir, &out_path, rel, OutputKind::Copied, &bytes, build.hash));
+                }
+                if !build.dry_run {
+                    println!("Copied {} -> {}", path.display(), out_path.display());
+                }
+            }
+        }
+        Ok(())
+    };
+
+    // Keep going past broken files so one run reports all of them
+    let mut failed: Vec<PathBuf> = Vec::new();
+    for path in &sources {
+        if let Err(e) = build_file(path) {
+            if build.fail_fast {
+                return Err(e);
+            }
+            eprintln!("[haystack] error: {}: {:#}", path.display(), e);
+            failed.push(path.clone());
+        }
+    }
+
+    if build.auto_index {
+        let outputs: HashSet<PathBuf> = sources
+            .iter()
+            .map(|p| output_rel_path(p.strip_prefix(src_dir).unwrap_or(p)))
+            .collect();
+        for (dir, mut page) in auto_index_pages(&listing, &outputs, opts.lang.as_deref().unwrap_or("en")) {
+            let out_path = out_dir.join(&dir).join("index.html");
+            page.meta.sidebar = tree.as_ref().map(|t| sidebar_html(t, &dir.join("index.html")));
+            let html = if build.fragment {
+                format_html(page.body, opts)
+            } else {
+                let index = src_dir.join(&dir).join("index.md");
+                let template = find_template(src_dir, &index);
+                wrap_html_page(page.body, &page.meta, &scoped_theme(src_dir, &index, theme)?, opts, template.as_deref())
+            };
+            let bytes = sink.write_text(&out_path, &html)?;
+            if build.manifest {
+                manifest.push(manifest_entry(out_dir, &out_path, Path::new(""), OutputKind::Generated, &bytes, build.hash));
+            }
+            if !build.dry_run {
+                println!("Indexed {} -> {}", src_dir.join(&dir).display(), out_path.display());
+            }
+        }
+    }
+
+    if build.single_page {
+        let html = single_page_bundle(bundle, theme, opts);
+        let out_path = out_dir.join(SINGLE_PAGE_FILE);
+        let bytes = sink.write_text(&out_path, &html)?;
+        if !build.dry_run {
+            println!("Bundled all pages -> {}", out_path.display());
+        }
+        if build.manifest {
+            manifest.push(manifest_entry(out_dir, &out_path, Path::new(""), OutputKind::Generated, &bytes, build.hash));
+        }
+    }
+    if build.fragment {
+        let css = fragment_css(theme, opts);
+        let out_path = out_dir.join(FRAGMENT_CSS_FILE);
+        let bytes = sink.write_text(&out_path, &css)?;
+        if build.manifest {
+            manifest.push(manifest_entry(out_dir, &out_path, Path::new(""), OutputKind::Generated, &bytes, build.hash));
+        }
+        if !build.dry_run {
+            println!("Wrote code styles {}", out_path.display());
+        }
+    }
+    if let (Some(limit), Some(base)) = (build.feed, opts.base_url.as_deref()) {
+        let title = listing
+            .iter()
+            .find(|(rel, _)| rel.with_extension("") == Path::new("index"))
+            .and_then(|(_, title)| title.clone())
+            .unwrap_or_else(|| base.to_string());
+        let out_path = out_dir.join(FEED_FILE);
+        let bytes = sink.write_text(&out_path, &atom_feed(base, &title, feed, limit))?;
+        if build.manifest {
+            manifest.push(manifest_entry(out_dir, &out_path, Path::new(""), OutputKind::Generated, &bytes, build.hash));
+        }
+        if !build.dry_run {
+            println!("Wrote feed {}", out_path.display());
+        }
+    }
+    match opts.base_url.as_deref() {
+        Some(base) => {
+            let out_path = out_dir.join(SITEMAP_FILE);
+            let bytes = sink.write_text(&out_path, &sitemap_xml(base, sitemap))?;
+            if build.manifest {
+                manifest.push(manifest_entry(out_dir, &out_path, Path::new(""), OutputKind::Generated, &bytes, build.hash));
+            }
+            if !build.dry_run {
+                println!("Wrote sitemap {}", out_path.display());
+            }
+        }
+        None if !build.dry_run => println!("No --base-url given, skipping {}", SITEMAP_FILE),
+        None => {}
+    }
+    if build.manifest {
+        write_manifest(&mut sink, out_dir, build.hash, manifest)?;
+    }
+    sink.report_stale(out_dir);
+    let mut broken_links = 0;
+    if let Some(check) = build.check_links {
+        let broken = check_external_links(&external_links, check);
+        for (url, problem) in &broken {
+            let pages = external_links[url].iter().map(|p| p.display().to_string()).collect::<Vec<_>>();
+            eprintln!("[haystack] broken link {} ({}) in {}", url, problem, pages.join(", "));
+        }
+        println!("Checked {} external link(s), {} broken", external_links.len(), broken.len());
+        broken_links = broken.len();
+    }
+    if !failed.is_empty() {
+        let list = failed.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join("\n  ");
+        return Err(anyhow!("{} file(s) failed to build:\n  {}", failed.len(), list));
+    }
+    let mut warnings = Vec::new();
+    if heading_skips > 0 {
+        warnings.push(format!("{} skipped heading level(s)", heading_skips));
+    }
+    if unknown_languages > 0 {
+        warnings.push(format!("{} code block(s) in an unknown language", unknown_languages));
+    }
+    if empty_alts > 0 {
+        warnings.push(format!("{} image(s) without alt text", empty_alts));
+    }
+    if broken_links > 0 {
+        warnings.push(format!("{} broken external link(s)", broken_links));
+    }
+    if build.strict && !warnings.is_empty() {
+        return Err(StrictWarnings(warnings.join(", ")).into());
+    }
+    Ok(())
+}
+
+const SINGLE_PAGE_FILE: &str = "all.html";
+
+const SITEMAP_FILE: &str = "sitemap.xml";
+
+/// A sitemaps.org `<urlset>` with one `<url>` per converted page, sorted by
+/// URL: `base` joined with the page's output path, and the source's
+/// modification date as `<lastmod>` when known.
+fn sitemap_xml(base: &str, mut pages: Vec<(String, Option<SystemTime>)>) -> String {
+    pages.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+    for (path, modified) in pages {
+        xml.push_str(&format!("<url><loc>{}/{}</loc>", escape_html(base), escape_html(&path)));
+        if let Some(modified) = modified {
+            xml.push_str(&format!("<lastmod>{}</lastmod>", iso_date(modified)));
+        }
+        xml.push_str("</url>\n");
+    }
+    xml.push_str("</urlset>\n");
+    xml
+}
+
+const FEED_FILE: &str = "feed.xml";
+
+/// A dated page, as listed in the `--feed`.
+struct FeedEntry {
+    title: String,
+    /// Output path relative to the site root
+    path: String,
+    /// RFC 3339, from `feed_timestamp`
+    updated: String,
+    summary: Option<String>,
+}
+
+/// A page `date` as an RFC 3339 timestamp: `YYYY-MM-DD` becomes midnight UTC,
+/// a date and time without offset (`YYYY-MM-DD HH:MM[:SS]`) is taken as UTC,
+/// and a full RFC 3339 timestamp is kept. Anything else is `None`.
+fn feed_timestamp(date: &str) -> Option<String> {
+    static RE_DATE: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r"^(\d{4}-\d{2}-\d{2})(?:[Tt ](\d{2}:\d{2})(:\d{2}(?:\.\d+)?)?([Zz]|[+-]\d{2}:\d{2})?)?$").unwrap()
+    });
+    let c = RE_DATE.captures(date.trim())?;
+    let time = c.get(2).map_or("00:00", |t| t.as_str());
+    let seconds = c.get(3).map_or(":00", |s| s.as_str());
+    let offset = c.get(4).map_or("Z", |o| o.as_str());
+    Some(format!("{}T{}{}{}", &c[1], time, seconds, offset.to_uppercase()))
+}
+
+/// Seconds and nanoseconds since the Unix epoch of a `feed_timestamp`, so
+/// timestamps with different UTC offsets compare by the instant they name.
+fn feed_instant(timestamp: &str) -> (i64, u32) {
+    let num = |s: Option<&str>| s.and_then(|s| s.parse::<i64>().ok()).unwrap_or(0);
+    let (year, month, day) = (num(timestamp.get(0..4)), num(timestamp.get(5..7)), num(timestamp.get(8..10)));
+    let time = timestamp.get(11..).unwrap_or("");
+    let (clock, offset) = time.split_at(time.find(['Z', '+', '-']).unwrap_or(time.len()));
+    let (seconds, fraction) = clock.get(6..).unwrap_or("").split_once('.').unwrap_or((clock.get(6..).unwrap_or(""), ""));
+    let nanos = format!("{:0<9}", &fraction[..fraction.len().min(9)]).parse().unwrap_or(0);
+    let offset = match offset.split_at_checked(1) {
+        Some((sign, hh_mm)) if sign == "+" || sign == "-" => {
+            let minutes = num(hh_mm.get(0..2)) * 60 + num(hh_mm.get(3..5));
+            if sign == "-" { -minutes * 60 } else { minutes * 60 }
+        }
+        _ => 0,
+    };
+    // Days since 1970-01-01 from a civil date (Howard Hinnant's algorithm)
+    let y = year - i64::from(month <= 2);
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let days = era * 146_097 + yoe * 365 + yoe / 4 - yoe / 100 + doy - 719_468;
+    let clock_secs = num(clock.get(0..2)) * 3_600 + num(clock.get(3..5)) * 60 + num(Some(seconds));
+    (days * 86_400 + clock_secs - offset, nanos)
+}
+
+/// An Atom feed of the `limit` newest `entries`, linked to `base`.
+fn atom_feed(base: &str, title: &str, mut entries: Vec<FeedEntry>, limit: usize) -> String {
+    entries.sort_by_cached_key(|e| (std::cmp::Reverse(feed_instant(&e.updated)), e.path.clone()));
+    entries.truncate(limit);
+    let updated = entries
+        .first()
+        .map(|e| e.updated.clone())
+        .unwrap_or_else(|| format!("{}T00:00:00Z", iso_date(SystemTime::now())));
+    let base = escape_html(base);
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    xml.push_str(&format!("<title>{}</title>\n", escape_html(title)));
+    xml.push_str(&format!("<id>{}/</id>\n", base));
+    xml.push_str(&format!("<link href=\"{}/\"/>\n", base));
+    xml.push_str(&format!("<link rel=\"self\" href=\"{}/{}\"/>\n", base, FEED_FILE));
+    xml.push_str(&format!("<updated>{}</updated>\n", updated));
+    for entry in &entries {
+        let url = format!("{}/{}", base, escape_html(&entry.path));
+        xml.push_str("<entry>\n");
+        xml.push_str(&format!("<title>{}</title>\n", escape_html(&entry.title)));
+        xml.push_str(&format!("<link href=\"{}\"/>\n<id>{}</id>\n", url, url));
+        xml.push_str(&format!("<updated>{}</updated>\n", entry.updated));
+        if let Some(summary) = &entry.summary {
+            xml.push_str(&format!("<summary>{}</summary>\n", escape_html(summary)));
+        }
+        xml.push_str("</entry>\n");
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+/// `YYYY-MM-DD` (UTC) of a timestamp.
+fn iso_date(time: SystemTime) -> String {
+    let secs = time.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = (secs / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Converts one `.md`/`.org` source and lays it out as `build` writes it.
+/// Returns the converted page and the output HTML.
+fn render_source_page(
+    src_dir: &Path,
+    path: &Path,
+    theme: &ThemeConfig,
+    opts: &RenderOptions,
+    build: &BuildOptions,
+    navs: &HashMap<PathBuf, PageNav>,
+    tree: Option<&SiteTree>,
+) -> Result<(ConvertedPage, String)> {
+    let rel = output_rel_path(path.strip_prefix(src_dir).unwrap_or(path));
+    let mut page = convert_file_body(path, opts)?;
+    page.meta.nav = navs.get(path).cloned();
+    page.meta.url_path = Some(slash_path(&rel));
+    page.meta.sidebar = tree.map(|t| sidebar_html(t, &rel));
+    let html = if build.fragment {
+        format_html(page.body.clone(), opts)
+    } else {
+        let template = find_template(src_dir, path);
+        let theme = scoped_theme(src_dir, path, theme)?;
+        wrap_html_page(page.body.clone(), &page.meta, &theme, opts, template.as_deref())
+    };
+    Ok((page, html))
+}
+
+/// How long `--watch` waits for more events before rebuilding, so the several
+/// events of one editor save make one rebuild.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// `build --watch`: a full build, then rebuilds as files under `src_dir`
+/// change. An edited page or static file is rebuilt on its own, along with
+/// every page that includes it (per the build's include graph); adding,
+/// removing or (un)drafting a file or changing a template or `_haystack.toml`
+/// rebuilds everything, as do edits when other pages show page titles
+/// (`--sidebar`, `--auto-index`, `--single-page`). Build errors are reported
+/// and watching goes on. Runs until interrupted.
+fn watch_build(src_dir: &Path, out_dir: &Path, theme: &ThemeConfig, opts: &RenderOptions, build: &BuildOptions) -> Result<()> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).context("starting file watcher")?;
+    let mut graph = IncludeGraph::new();
+    if let Err(e) = build_site(src_dir, out_dir, theme, opts, build, &mut graph) {
+        eprintln!("[haystack] error: {:#}", e);
+    }
+    watcher
+        .watch(src_dir, notify::RecursiveMode::Recursive)
+        .with_context(|| format!("watching {}", src_dir.display()))?;
+    let mut opts = opts.clone();
+    if build.img_dimensions {
+        opts.image_sizes = Some(ImageSizes::new(src_dir));
+    }
+    let opts = &opts;
+    let published = |sources: Vec<PathBuf>| -> HashSet<PathBuf> { sources.into_iter().filter(|p| !is_draft(p)).collect() };
+    let mut sources = published(collect_sources(src_dir, false, opts.follow_symlinks)?);
+    let src_abs = fs::canonicalize(src_dir).with_context(|| format!("resolving {}", src_dir.display()))?;
+    // Rewrites the output of one changed source, as build_all would
+    let rebuild = |path: &Path, navs: &HashMap<PathBuf, PageNav>, sink: &mut OutputSink, graph: &mut IncludeGraph| -> Result<()> {
+        let rel = path.strip_prefix(src_dir).unwrap_or(path);
+        if !is_page(path) {
+            let out_path = out_dir.join(rel);
+            sink.copy(path, &out_path)?;
+            println!("Copied {} -> {}", path.display(), out_path.display());
+            return Ok(());
+        }
+        let out_path = out_dir.join(output_rel_path(rel));
+        let (page, html) = render_source_page(src_dir, path, theme, opts, build, navs, None)?;
+        graph.insert(path.to_path_buf(), page.meta.includes.iter().map(|p| normalize_path(p)).collect());
+        sink.write_text(&out_path, &html)?;
+        if opts.include_source {
+            sink.copy(path, &out_dir.join(rel))?;
+        }
+        println!("Built {} -> {}", path.display(), out_path.display());
+        Ok(())
+    };
+    println!("Watching {} for changes (Ctrl-C to stop)", src_dir.display());
+
+    loop {
+        let mut changed: BTreeSet<PathBuf> = BTreeSet::new();
+        let mut event = rx.recv().context("file watcher stopped")?;
+        loop {
+            match event {
+                Ok(ev) if !matches!(ev.kind, notify::EventKind::Access(_)) => {
+                    // Events carry absolute paths; sources are under src_dir as given
+                    changed.extend(ev.paths.iter().filter_map(|p| Some(src_dir.join(p.strip_prefix(&src_abs).ok()?))))
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("[haystack] watch error: {}", e),
+            }
+            match rx.recv_timeout(WATCH_DEBOUNCE) {
+                Ok(next) => event = next,
+                Err(_) => break,
+            }
+        }
+        let now = match collect_sources(src_dir, build.strict, opts.follow_symlinks) {
+            Ok(now) => published(now),
+            Err(e) => {
+                eprintln!("[haystack] error: {:#}", e);
+                continue;
+            }
+        };
+        let pages_changed = changed.iter().any(|p| now.contains(p) && is_page(p));
+        let full = now != sources
+            || changed.iter().any(|p| p.file_name().is_some_and(|n| n == TEMPLATE_FILE || n == DIR_CONFIG_FILE))
+            || (pages_changed && (opts.sidebar || build.auto_index || build.single_page));
+        sources = now;
+        if full {
+            if let Err(e) = build_site(src_dir, out_dir, theme, opts, build, &mut graph) {
+                eprintln!("[haystack] error: {:#}", e);
+            }
+            continue;
+        }
+        let all: Vec<PathBuf> = sources.iter().cloned().collect();
+        let navs = if pages_changed { page_navs(&all) } else { HashMap::new() };
+        let mut sink = OutputSink::new(build);
+        // Changed sources, plus every page including a changed file
+        let changed_normalized: HashSet<PathBuf> = changed.iter().map(|p| normalize_path(p)).collect();
+        let mut targets: BTreeSet<PathBuf> = changed.iter().filter(|p| sources.contains(*p)).cloned().collect();
+        targets.extend(
+            graph
+                .iter()
+                .filter(|(page, includes)| sources.contains(*page) && includes.iter().any(|i| changed_normalized.contains(i)))
+                .map(|(page, _)| page.clone()),
+        );
+        for path in &targets {
+            if let Err(e) = rebuild(path, &navs, &mut sink, &mut graph) {
+                eprintln!("[haystack] error: {}: {:#}", path.display(), e);
+            }
+        }
+    }
 }
 
-#[derive(Debug, Clone, Default)]
-struct ThemeConfig {
-    light: Option<String>,
-    dark: Option<String>,
+/// `path` with `.` and `a/..` components removed, without touching the
+/// filesystem.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir if matches!(out.components().next_back(), Some(std::path::Component::Normal(_))) => {
+                out.pop();
+            }
+            other => out.push(other),
+        }
+    }
+    out
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+fn is_page(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "md" || ext == "org")
+}
 
-    match cli.command {
-        Commands::Build { theme_light, theme_dark } => {
-            let src = Path::new("src");
-            let out = Path::new("output");
-            let theme = ThemeConfig { light: theme_light, dark: theme_dark };
-            build_all(src, out, &theme)?;
-        }
-        Commands::Serve { port, theme_light, theme_dark } => {
-            let src = Path::new("src");
-            let theme = ThemeConfig { light: theme_light, dark: theme_dark };
-            serve(port, src, &theme)?;
+/// Distinct `http://` and `https://` link and image URLs in rendered HTML.
+fn external_urls(html: &str) -> BTreeSet<String> {
+    static RE_EXTERNAL: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r##"\s(?:href|src)="(https?://[^"#]+)[^"]*""##).unwrap());
+    RE_EXTERNAL
+        .captures_iter(html)
+        .map(|c| c[1].replace("&amp;", "&"))
+        .collect()
+}
+
+/// Requests every URL (HEAD, falling back to GET for servers that refuse HEAD)
+/// on up to `check.concurrency` threads. Returns the URLs that failed with a
+/// short description of the problem, in URL order.
+fn check_external_links(links: &BTreeMap<String, Vec<PathBuf>>, check: LinkCheck) -> Vec<(String, String)> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(check.timeout)
+        .user_agent(concat!("haystack/", env!("CARGO_PKG_VERSION")))
+        .build();
+    let queue = Mutex::new(links.keys());
+    let broken = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..check.concurrency.min(links.len()) {
+            scope.spawn(|| loop {
+                let Some(url) = queue.lock().unwrap().next() else { break };
+                let mut result = agent.head(url).call();
+                if let Err(ureq::Error::Status(405 | 501, _)) = result {
+                    result = agent.get(url).call();
+                }
+                let problem = match result {
+                    Ok(_) => continue,
+                    Err(ureq::Error::Status(code, response)) => format!("{} {}", code, response.status_text()),
+                    Err(ureq::Error::Transport(e)) => {
+                        let message = e.to_string();
+                        message.strip_prefix(&format!("{}: ", url)).unwrap_or(&message).to_string()
+                    }
+                };
+                broken.lock().unwrap().push((url.clone(), problem));
+            });
         }
-        Commands::Themes => {
-            list_themes();
+    });
+    let mut broken = broken.into_inner().unwrap();
+    broken.sort();
+    broken
+}
+
+/// Stylesheet written next to `--fragment` partials.
+const FRAGMENT_CSS_FILE: &str = "haystack.css";
+
+/// Code highlighting for `--fragment` output: the light theme, switching to
+/// the dark one with the reader's color scheme. Rules are scoped to `.hl` so
+/// they don't leak into the embedding page.
+fn fragment_css(theme: &ThemeConfig, opts: &RenderOptions) -> String {
+    let (light, dark) = syntax_css(theme);
+    let background = opts.code_theme_bg.then_some("pre:has(> code.hl)");
+    format!(
+        "/* haystack code highlighting */\n{}\n@media (prefers-color-scheme: dark) {{\n{}\n}}\n{}",
+        scope_syntect_css(&light, ".hl", background),
+        scope_syntect_css(&dark, ".hl", background),
+        language_theme_css(opts, "body")
+    )
+}
+
+/// Listing pages for every directory (relative to the source root) that holds
+/// pages, directly or below, but has no `index.html` output of its own. Each
+/// links to its subdirectories, then its pages by title (file name when the
+/// page has none).
+fn auto_index_pages(
+    pages: &[(PathBuf, Option<String>)],
+    outputs: &HashSet<PathBuf>,
+    lang: &str,
+) -> Vec<(PathBuf, ConvertedPage)> {
+    /// Subdirectory names and `(title, file name)` of pages in one directory
+    #[derive(Default)]
+    struct Listing {
+        subdirs: Vec<String>,
+        entries: Vec<(String, String)>,
+    }
+    let mut dirs: BTreeMap<PathBuf, Listing> = BTreeMap::new();
+    for (rel, title) in pages {
+        let dir = rel.parent().unwrap_or(Path::new("")).to_path_buf();
+        let name = output_rel_path(rel).file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let title = title.clone().unwrap_or_else(|| rel.file_stem().unwrap_or_default().to_string_lossy().into_owned());
+        dirs.entry(dir.clone()).or_default().entries.push((title, name));
+        // Make sure every ancestor lists the directory below it
+        let mut child = dir;
+        while let Some(parent) = child.parent() {
+            let child_name = child.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            let subdirs = &mut dirs.entry(parent.to_path_buf()).or_default().subdirs;
+            if !subdirs.contains(&child_name) {
+                subdirs.push(child_name);
+            }
+            child = parent.to_path_buf();
         }
     }
+    dirs.into_iter()
+        .filter(|(dir, _)| !outputs.contains(&dir.join("index.html")))
+        .map(|(dir, Listing { mut subdirs, mut entries })| {
+            subdirs.sort();
+            entries.sort_by_key(|(title, name)| (title.to_lowercase(), name.clone()));
+            let heading = if dir.as_os_str().is_empty() { "Index".to_string() } else { format!("{}/", slash_path(&dir)) };
+            let mut body = format!("<h1>{}</h1>\n<ul class=\"auto-index\">\n", escape_html(&heading));
+            for sub in &subdirs {
+                body.push_str(&format!(
+                    "<li class=\"dir\"><a href=\"{0}/index.html\">{1}/</a></li>\n",
+                    escape_html(sub),
+                    escape_html(sub)
+                ));
+            }
+            for (title, name) in &entries {
+                body.push_str(&format!("<li><a href=\"{}\">{}</a></li>\n", escape_html(name), escape_html(title)));
+            }
+            body.push_str("</ul>\n");
+            let meta = PageMeta { title: Some(heading), lang: lang.to_string(), ..PageMeta::default() };
+            (dir, ConvertedPage { body, meta })
+        })
+        .collect()
+}
+
+/// Joins every page into one document: pages are ordered by `order` (pages
+/// without one last) then path, each becomes a `<section id="page-...">`, ids
+/// are prefixed per page so they stay unique, links between pages turn into
+/// in-page anchors and relative asset URLs are rebased to the output root.
+fn single_page_bundle(mut pages: Vec<(PathBuf, ConvertedPage)>, theme: &ThemeConfig, opts: &RenderOptions) -> String {
+    static RE_ID: Lazy<Regex> = Lazy::new(|| Regex::new(r#"\s(id|data-task)="([^"]*)""#).unwrap());
+    static RE_URL: Lazy<Regex> = Lazy::new(|| Regex::new(r#"\s(href|src)="([^"]*)""#).unwrap());
+    pages.sort_by(|(a_path, a), (b_path, b)| {
+        (a.meta.order.is_none(), a.meta.order, a_path).cmp(&(b.meta.order.is_none(), b.meta.order, b_path))
+    });
+    let page_ids: std::collections::HashMap<String, String> = pages
+        .iter()
+        .map(|(rel, _)| {
+            let html_path = slash_path(&rel.with_extension("html"));
+            let id = format!("page-{}", slugify(&slash_path(&rel.with_extension("")).replace('/', " ")));
+            (html_path, id)
+        })
+        .collect();
+
+    let mut body = String::new();
+    for (rel, page) in &pages {
+        let page_id = &page_ids[&slash_path(&rel.with_extension("html"))];
+        let dir = rel.parent().map(slash_path).unwrap_or_default();
+        let html = RE_ID.replace_all(&page.body, |caps: &regex::Captures| {
+            format!(" {}=\"{}--{}\"", &caps[1], page_id, &caps[2])
+        });
+        let html = RE_URL.replace_all(&html, |caps: &regex::Captures| {
+            let url = &caps[2];
+            let rewritten = if let Some(frag) = url.strip_prefix('#') {
+                format!("#{}--{}", page_id, frag)
+            } else if url.contains("://") || url.starts_with("//") || url.starts_with("data:") || url.starts_with("mailto:") {
+                url.to_string()
+            } else {
+                let (path, frag) = url.split_once('#').unwrap_or((url, ""));
+                let path = path.strip_prefix(opts.base_path.as_deref().unwrap_or("")).unwrap_or(path);
+                let target = match path.strip_prefix('/') {
+                    Some(root_relative) => root_relative.to_string(),
+                    None => normalize_rel_url(&dir, path),
+                };
+                match page_ids.get(&target) {
+                    Some(id) if frag.is_empty() => format!("#{}", id),
+                    Some(id) => format!("#{}--{}", id, frag),
+                    None if url.starts_with('/') => url.to_string(),
+                    None => target,
+                }
+            };
+            format!(" {}=\"{}\"", &caps[1], rewritten)
+        });
+        body.push_str(&format!("<section class=\"page\" id=\"{}\">\n{}\n</section>\n", page_id, html));
+    }
+    let meta = PageMeta {
+        title: pages.first().and_then(|(_, p)| p.meta.title.clone()),
+        lang: opts.lang.clone().unwrap_or_else(|| "en".to_string()),
+        has_tasks: pages.iter().any(|(_, p)| p.meta.has_tasks),
+        has_math: pages.iter().any(|(_, p)| p.meta.has_math),
+        has_mermaid: pages.iter().any(|(_, p)| p.meta.has_mermaid),
+        ..PageMeta::default()
+    };
+    wrap_html_page(body, &meta, theme, opts, None)
+}
 
-    Ok(())
+/// Whether an image URL can serve as a link preview: not a data URI or SVG.
+fn is_preview_image(url: &str) -> bool {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    !url.starts_with("data:") && !path.to_ascii_lowercase().ends_with(".svg")
 }
 
-fn build_all(src_dir: &Path, out_dir: &Path, theme: &ThemeConfig) -> Result<()> {
-    if !src_dir.exists() {
-        return Err(anyhow!("src folder not found: {}", src_dir.display()));
+/// `url` as written on the page at `page_path` (relative to the site root),
+/// made absolute: full URLs are kept, protocol-relative ones get `https:`, and
+/// root-relative or relative ones need `base_url`.
+fn absolute_url(url: &str, page_path: Option<&str>, base_url: Option<&str>) -> Option<String> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        return Some(url.to_string());
     }
-    fs::create_dir_all(out_dir).with_context(|| format!("creating {}", out_dir.display()))?;
-
-    for entry in WalkDir::new(src_dir).into_iter().filter_map(|e| e.ok()) {
-        let path = entry.path();
-        if path.is_file() {
-            match path.extension().and_then(|s| s.to_str()) {
-                Some("md") | Some("org") => {
-                    let rel = path.strip_prefix(src_dir).unwrap();
-                    let mut out_path = out_dir.to_path_buf();
-                    let file_stem = rel.with_extension("");
-                    // Keep subdirectories structure
-                    out_path.push(file_stem);
-                    out_path.set_extension("html");
-
-                    if let Some(parent) = out_path.parent() {
-                        fs::create_dir_all(parent)?;
-                    }
+    if let Some(rest) = url.strip_prefix("//") {
+        return Some(format!("https://{}", rest));
+    }
+    let base = base_url?;
+    if url.starts_with('/') {
+        return Some(format!("{}{}", base, url));
+    }
+    let dir = page_path.and_then(|p| p.rsplit_once('/')).map_or("", |(dir, _)| dir);
+    Some(format!("{}/{}", base, normalize_rel_url(dir, url)))
+}
 
-                    let html = convert_file(path, theme)?;
-                    fs::write(&out_path, html).with_context(|| format!(
-                        "writing output file {}",
-                        out_path.display()
-                    ))?;
-                    println!(
-                        "Built {} -> {}",
+/// Joins a relative URL onto a `/`-separated directory, resolving `.` and `..`.
+fn normalize_rel_url(dir: &str, url: &str) -> String {
+    let mut parts: Vec<&str> = dir.split('/').filter(|p| !p.is_empty()).collect();
+    for seg in url.split('/') {
+        match seg {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            other => parts.push(other),
+        }
+    }
+    parts.join("/")
+}
+
+/// Files under `src_dir` to build, in walk order. When several sources map to
+/// the same output path, only the one `serve` would pick is kept (a static
+/// `.html` file, then `.md`, then `.org`); the others are reported as warnings,
+/// or as an error with `--strict`.
+fn collect_sources(src_dir: &Path, strict: bool, follow_symlinks: bool) -> Result<Vec<PathBuf>> {
+    let files: Vec<PathBuf> = source_files(src_dir, follow_symlinks)
+        .into_iter()
+        .filter(|p| p.file_name().is_some_and(|n| n != TEMPLATE_FILE && n != DIR_CONFIG_FILE))
+        .collect();
+    let precedence = |path: &Path| match path.extension().and_then(|s| s.to_str()) {
+        Some("md") => 1,
+        Some("org") => 2,
+        _ => 0,
+    };
+    let mut by_output: BTreeMap<PathBuf, Vec<&PathBuf>> = BTreeMap::new();
+    for file in &files {
+        let rel = file.strip_prefix(src_dir).unwrap_or(file);
+        by_output.entry(output_rel_path(rel)).or_default().push(file);
+    }
+    let mut skipped = HashSet::new();
+    let mut collisions = Vec::new();
+    for (out_rel, mut sources) in by_output {
+        if sources.len() < 2 {
+            continue;
+        }
+        sources.sort_by_key(|p| precedence(p));
+        let (used, rest) = sources.split_first().unwrap();
+        let others = rest.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(", ");
+        collisions.push(format!("{} is produced by {} (used) and {} (skipped)", slash_path(&out_rel), used.display(), others));
+        skipped.extend(rest.iter().map(|p| p.to_path_buf()));
+    }
+    if strict && !collisions.is_empty() {
+        return Err(StrictWarnings(format!("conflicting sources:\n  {}", collisions.join("\n  "))).into());
+    }
+    for c in &collisions {
+        eprintln!("[haystack] warning: {}", c);
+    }
+    Ok(files.into_iter().filter(|f| !skipped.contains(f)).collect())
+}
+
+/// Every file under `src_dir`. Symlinked files are always included; symlinked
+/// directories only with `follow_symlinks`, where a link back to one of its own
+/// ancestors is reported and skipped.
+fn source_files(src_dir: &Path, follow_symlinks: bool) -> Vec<PathBuf> {
+    WalkDir::new(src_dir)
+        .follow_links(follow_symlinks)
+        .into_iter()
+        .filter_map(|e| match e {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                if let (Some(path), Some(ancestor)) = (e.path(), e.loop_ancestor()) {
+                    eprintln!(
+                        "[haystack] skipping {}: symlink loop back to {}",
                         path.display(),
-                        out_path.display()
+                        ancestor.display()
                     );
                 }
-                _ => {
-                    // Copy static files as-is
-                    let rel = path.strip_prefix(src_dir).unwrap();
-                    let mut out_path = out_dir.to_path_buf();
-                    out_path.push(rel);
-                    if let Some(parent) = out_path.parent() {
-                        fs::create_dir_all(parent)?;
-                    }
-                    fs::copy(path, &out_path).with_context(|| format!(
-                        "copying static {} -> {}",
-                        path.display(),
-                        out_path.display()
-                    ))?;
-                    println!("Copied {} -> {}", path.display(), out_path.display());
-                }
+                None
             }
-        }
+        })
+        .map(|e| e.into_path())
+        .filter(|p| p.is_file())
+        .collect()
+}
+
+/// Output path of a source relative to the source root: pages become `.html`,
+/// everything else keeps its name.
+fn output_rel_path(rel: &Path) -> PathBuf {
+    match rel.extension().and_then(|s| s.to_str()) {
+        Some("md") | Some("org") => rel.with_extension("html"),
+        _ => rel.to_path_buf(),
+    }
+}
+
+fn manifest_entry(
+    out_dir: &Path,
+    out_path: &Path,
+    source: &Path,
+    kind: OutputKind,
+    bytes: &[u8],
+    hash: HashAlgorithm,
+) -> ManifestEntry {
+    ManifestEntry {
+        path: slash_path(out_path.strip_prefix(out_dir).unwrap_or(out_path)),
+        source: slash_path(source),
+        kind,
+        size: bytes.len() as u64,
+        digest: BTreeMap::from([(hash.name(), content_hash(hash, bytes))]),
+        dependencies: Vec::new(),
+    }
+}
+
+/// Writes the manifest sorted by output path so successive builds diff cleanly.
+fn write_manifest(sink: &mut OutputSink, out_dir: &Path, hash: HashAlgorithm, mut files: Vec<ManifestEntry>) -> Result<()> {
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    let json = serde_json::to_string_pretty(&Manifest { hash, files })?;
+    let path = out_dir.join(MANIFEST_FILE);
+    sink.write_text(&path, &json).context("writing manifest")?;
+    if !sink.dry_run {
+        println!("Wrote manifest {}", path.display());
     }
     Ok(())
 }
 
-fn serve(port: u16, src_dir: &Path, theme: &ThemeConfig) -> Result<()> {
+/// Destination for `build` output. Normally writes files (creating parent
+/// directories); with `--dry-run` nothing is touched and each file is reported
+/// as `create`, `update` or `unchanged` compared to what is on disk.
+struct OutputSink {
+    dry_run: bool,
+    final_newline: bool,
+    gzip: bool,
+    written: HashSet<PathBuf>,
+}
+
+/// Outputs `--gzip-output` writes a `.gz` copy of.
+const GZIP_EXTENSIONS: [&str; 7] = ["html", "css", "js", "mjs", "json", "xml", "svg"];
+
+impl OutputSink {
+    fn new(build: &BuildOptions) -> Self {
+        OutputSink {
+            dry_run: build.dry_run,
+            final_newline: build.final_newline,
+            gzip: build.gzip_output,
+            written: HashSet::new(),
+        }
+    }
+
+    /// Writes a generated text file ending in exactly one newline (none with
+    /// `--no-final-newline`). Returns the bytes written, for the manifest.
+    fn write_text(&mut self, path: &Path, text: &str) -> Result<Vec<u8>> {
+        let mut bytes = text.trim_end_matches(['\n', '\r']).as_bytes().to_vec();
+        if self.final_newline {
+            // `--line-endings crlf` pages keep CRLF on the last line too
+            let newline: &[u8] = if text.contains("\r\n") { b"\r\n" } else { b"\n" };
+            bytes.extend_from_slice(newline);
+        }
+        self.write(path, &bytes)?;
+        Ok(bytes)
+    }
+
+    fn write(&mut self, path: &Path, contents: &[u8]) -> Result<()> {
+        self.write_file(path, contents)?;
+        self.write_gzip(path, contents)
+    }
+
+    fn write_file(&mut self, path: &Path, contents: &[u8]) -> Result<()> {
+        self.written.insert(path.to_path_buf());
+        if self.dry_run {
+            let action = match fs::read(path) {
+                Ok(existing) if existing == contents => "unchanged",
+                Ok(_) => "update",
+                Err(_) => "create",
+            };
+            println!("{} {}", action, path.display());
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("creating {}", parent.display()))?;
+        }
+        fs::write(path, contents).with_context(|| format!("writing output file {}", path.display()))
+    }
+
+    fn copy(&mut self, from: &Path, to: &Path) -> Result<()> {
+        if self.dry_run {
+            let bytes = fs::read(from).with_context(|| format!("reading {}", from.display()))?;
+            return self.write(to, &bytes);
+        }
+        self.written.insert(to.to_path_buf());
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("creating {}", parent.display()))?;
+        }
+        fs::copy(from, to).with_context(|| format!("copying static {} -> {}", from.display(), to.display()))?;
+        if self.gzip && is_gzip_output(to) {
+            let bytes = fs::read(from).with_context(|| format!("reading {}", from.display()))?;
+            self.write_gzip(to, &bytes)?;
+        }
+        Ok(())
+    }
+
+    /// With `--gzip-output`, writes `path.gz` for text outputs. The level is
+    /// fixed and the gzip header carries no name or timestamp, so the same
+    /// input always gives the same bytes.
+    fn write_gzip(&mut self, path: &Path, contents: &[u8]) -> Result<()> {
+        if !self.gzip || !is_gzip_output(path) {
+            return Ok(());
+        }
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(contents)?;
+        let gz = encoder.finish()?;
+        let mut gz_path = path.as_os_str().to_owned();
+        gz_path.push(".gz");
+        self.write_file(Path::new(&gz_path), &gz)
+    }
+
+    /// With `--dry-run`, lists files already in `out_dir` that this build
+    /// doesn't produce. A real build leaves them in place.
+    fn report_stale(&self, out_dir: &Path) {
+        if !self.dry_run {
+            return;
+        }
+        for entry in WalkDir::new(out_dir).into_iter().filter_map(|e| e.ok()) {
+            if entry.path().is_file() && !self.written.contains(entry.path()) {
+                println!("delete {} (stale)", entry.path().display());
+            }
+        }
+    }
+}
+
+/// Text files worth a gzipped copy; dotfiles like the manifest are skipped.
+fn is_gzip_output(path: &Path) -> bool {
+    let hidden = path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with('.'));
+    let ext = path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+    !hidden && ext.is_some_and(|e| GZIP_EXTENSIONS.contains(&e.as_str()))
+}
+
+/// Relative path with `/` separators regardless of platform.
+fn slash_path(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn serve(src_dir: &Path, theme: &ThemeConfig, opts: &RenderOptions, serve: &ServeOptions) -> Result<()> {
     if !src_dir.exists() {
         return Err(anyhow!("src folder not found: {}", src_dir.display()));
     }
-    let addr = format!("0.0.0.0:{}", port);
-    println!("Serving {} on http://{}/", src_dir.display(), addr);
-    let server = Server::http(addr).map_err(|e| anyhow!("server error: {e}"))?;
+    let addr = format!("0.0.0.0:{}", serve.port);
+    let server = match &serve.tls {
+        Some((cert, key)) => {
+            let ssl = SslConfig {
+                certificate: fs::read(cert).with_context(|| format!("reading certificate {}", cert.display()))?,
+                private_key: fs::read(key).with_context(|| format!("reading private key {}", key.display()))?,
+            };
+            println!("Serving {} on https://{}/", src_dir.display(), addr);
+            Server::https(addr, ssl)
+        }
+        None => {
+            println!("Serving {} on http://{}/", src_dir.display(), addr);
+            Server::http(addr)
+        }
+    }
+    .map_err(|e| anyhow!("server error: {e}"))?;
+    if serve.open {
+        let scheme = if serve.tls.is_some() { "https" } else { "http" };
+        let url = format!("{}://localhost:{}/", scheme, serve.port);
+        if let Err(e) = open::that_detached(&url) {
+            eprintln!("[haystack] warning: could not open browser at {}: {}", url, e);
+        }
+    }
 
-    for request in server.incoming_requests() {
+    let mut cache = serve.cache.map(PageCache::new);
+    let fail = |status: u16, failure: Option<(&Path, &anyhow::Error)>| {
+        error_response(status, failure, serve.error_format, theme, opts)
+    };
+    let cors = |resp: HttpResponse| match &serve.cors {
+        Some(origin) => resp.with_header(Header::from_bytes(&b"Access-Control-Allow-Origin"[..], origin.as_bytes()).unwrap()),
+        None => resp,
+    };
+    let health = format!(r#"{{"status":"ok","src":{}}}"#, serde_json::Value::from(src_dir.display().to_string()));
+    let mut live = match opts.live_reload {
+        true => Some(LiveReload::new(src_dir)?),
+        false => None,
+    };
+    loop {
+        let request = match live.as_mut() {
+            Some(live) => {
+                live.tick();
+                server.recv_timeout(LIVE_RELOAD_TICK)
+            }
+            None => server.recv().map(Some),
+        };
+        let request = match request {
+            Ok(Some(request)) => request,
+            Ok(None) => continue,
+            Err(e) => return Err(anyhow!("server error: {e}")),
+        };
         let url_path = request.url(); // includes leading '/'
         let mut path = url_path.split('?').next().unwrap_or("").trim_start_matches('/');
+        // Answered before routing so no file under src/ can shadow it
+        if HEALTH_PATHS.contains(&path) {
+            let resp = Response::from_string(health.as_str()).with_header(content_type_header(JSON_CONTENT_TYPE));
+            let _ = request.respond(cors(resp));
+            continue;
+        }
+        // Preflights carry no credentials, so they're answered before --auth
+        if serve.cors.is_some() && *request.method() == Method::Options {
+            let resp = cors(cors_preflight_response(request_header(&request, "Access-Control-Request-Headers")));
+            let _ = request.respond(resp);
+            continue;
+        }
+        if let Some(expected) = &serve.auth {
+            if !basic_auth_matches(request_header(&request, "Authorization"), expected) {
+                let resp = fail(401, None)
+                    .with_header(Header::from_bytes(&b"WWW-Authenticate"[..], &b"Basic realm=\"haystack\", charset=\"UTF-8\""[..]).unwrap());
+                let _ = request.respond(cors(resp));
+                continue;
+            }
+        }
+        if let Some(live) = live.as_mut().filter(|_| path == LIVE_RELOAD_PATH) {
+            live.poll(request);
+            continue;
+        }
         if path.is_empty() {
             path = "index.html";
         }
+        let encodings = match serve.compress {
+            true => accepted_encodings(request_header(&request, "Accept-Encoding")),
+            false => Vec::new(),
+        };
 
         // Basic path traversal guard
         if path.split('/').any(|seg| seg == ".." || seg.contains('\\')) {
-            let resp = Response::from_string("Bad Request").with_status_code(400);
-            let _ = request.respond(resp);
+            let _ = request.respond(cors(fail(400, None)));
             continue;
         }
 
@@ -159,112 +2174,834 @@ fn serve(port: u16, src_dir: &Path, theme: &ThemeConfig) -> Result<()> {
         let is_html_route = path.ends_with(".html") || !has_ext;
 
         let resp = if is_html_route {
-            let base = if path.ends_with(".html") { &path[..path.len() - ".html".len()] } else { path };
+            let base = path.strip_suffix(".html").unwrap_or(path);
             let html_path = src_dir.join(format!("{}.html", base));
             let md_path = src_dir.join(format!("{}.md", base));
             let org_path = src_dir.join(format!("{}.org", base));
 
-            if html_path.exists() {
-                match fs::read_to_string(&html_path) {
-                    Ok(s) => Response::from_string(s)
-                        .with_status_code(200)
-                        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/html; charset=utf-8"[..]).unwrap()),
-                    Err(e) => Response::from_string(format!("Error reading {}: {}", html_path.display(), e))
-                        .with_status_code(500),
-                }
-            } else if md_path.exists() {
-                match fs::read_to_string(&md_path).map(|s| convert_markdown_to_html(&s, theme)) {
-                    Ok(html) => Response::from_string(html)
-                        .with_status_code(200)
-                        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/html; charset=utf-8"[..]).unwrap()),
-                    Err(e) => Response::from_string(format!("Error reading {}: {}", md_path.display(), e))
-                        .with_status_code(500),
-                }
-            } else if org_path.exists() {
-                match fs::read_to_string(&org_path).map(|s| convert_org_to_html(&s, theme)) {
-                    Ok(html) => Response::from_string(html)
-                        .with_status_code(200)
-                        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/html; charset=utf-8"[..]).unwrap()),
-                    Err(e) => Response::from_string(format!("Error reading {}: {}", org_path.display(), e))
-                        .with_status_code(500),
+            if html_path.file_name().is_some_and(|n| n == TEMPLATE_FILE) {
+                fail(404, None)
+            } else if html_path.exists() {
+                match fs::read_to_string(&html_path).with_context(|| format!("reading {}", html_path.display())) {
+                    Ok(s) => encoded_response(s.into_bytes(), HTML_CONTENT_TYPE, encodings.first().copied()),
+                    Err(e) => fail(500, Some((&html_path, &e))),
+                }
+            } else if let Some(source) = [md_path, org_path].into_iter().find(|p| p.exists()) {
+                let render = || convert_file(&source, src_dir, theme, opts);
+                let html = match cache.as_mut() {
+                    Some(cache) => cache.get_or_render(&source, render),
+                    None => render().map(|(html, _)| html),
+                };
+                match html {
+                    Ok(html) => encoded_response(html.into_bytes(), HTML_CONTENT_TYPE, encodings.first().copied()),
+                    Err(e) => fail(500, Some((&source, &e))),
                 }
             } else {
-                Response::from_string("Not Found").with_status_code(404)
+                fail(404, None)
             }
         } else {
             // Serve static file from src/
             let static_path = src_dir.join(path);
-            if static_path.is_file() {
-                match fs::read(&static_path) {
-                    Ok(bytes) => {
-                        let mime = mime_guess::from_path(&static_path).first_or_octet_stream();
-                        let mut resp = Response::from_data(bytes).with_status_code(200);
-                        let header = Header::from_bytes(&b"Content-Type"[..], mime.to_string().as_bytes()).unwrap();
-                        resp = resp.with_header(header);
-                        resp
-                    }
-                    Err(e) => Response::from_string(format!("Error reading {}: {}", static_path.display(), e)).with_status_code(500),
+            let range = request_header(&request, "Range");
+            if static_path.file_name().is_some_and(|n| n == DIR_CONFIG_FILE) {
+                fail(404, None)
+            } else if static_path.is_file() {
+                let precompressed = match range {
+                    Some(_) => None,
+                    None => encodings.iter().find_map(|&e| fresh_sibling(&static_path, e).map(|file| (e, file))),
+                };
+                match precompressed {
+                    Some((encoding, file)) => serve_precompressed(&static_path, &file, encoding),
+                    None => serve_static(&static_path, range, encodings.first().copied()),
                 }
+                .unwrap_or_else(|e| fail(500, Some((&static_path, &e))))
             } else {
-                Response::from_string("Not Found").with_status_code(404)
+                fail(404, None)
+            }
+        };
+
+        let _ = request.respond(cors(resp));
+    }
+}
+
+/// `204` answer to a `--cors` preflight `OPTIONS` request, allowing the
+/// methods `serve` handles and whatever headers the browser asked for.
+fn cors_preflight_response(request_headers: Option<&str>) -> HttpResponse {
+    let header = |name: &str, value: &str| Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap();
+    let mut resp = Response::from_data(Vec::new())
+        .with_status_code(204)
+        .with_header(header("Access-Control-Allow-Methods", "GET, HEAD, OPTIONS"))
+        .with_header(header("Access-Control-Max-Age", "600"));
+    if let Some(headers) = request_headers {
+        resp = resp.with_header(header("Access-Control-Allow-Headers", headers));
+    }
+    resp
+}
+
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+/// Liveness routes of `serve`, answered without touching the filesystem.
+/// `_health` is the original spelling; `_haystack/health` is namespaced so
+/// it can't collide with site content.
+const HEALTH_PATHS: [&str; 2] = ["_haystack/health", "_health"];
+
+/// Long-poll route of `serve --live-reload`. The `__haystack` prefix keeps it
+/// clear of site files; one that does exist is reported at startup.
+const LIVE_RELOAD_PATH: &str = "__haystack/live-reload";
+
+/// How often `serve --live-reload` checks for file changes between requests.
+const LIVE_RELOAD_TICK: Duration = Duration::from_millis(100);
+
+/// Longest a reload poll is held open before it is answered unchanged (the
+/// page then polls again), so idle connections aren't cut by proxies.
+const LIVE_RELOAD_HOLD: Duration = Duration::from_secs(30);
+
+/// `serve --live-reload`: watches the source folder and answers pages' long
+/// polls when it changes. Each poll carries the generation the page last saw
+/// (`?since=`) and is held until the generation moves on, so a page reloads
+/// once per burst of changes. Generations include the server's start time, so
+/// pages also reload after a restart.
+struct LiveReload {
+    _watcher: notify::RecommendedWatcher,
+    events: std::sync::mpsc::Receiver<notify::Result<notify::Event>>,
+    started: u64,
+    changes: u64,
+    /// When the latest change not yet announced happened
+    pending: Option<std::time::Instant>,
+    /// Held polls and when they arrived
+    waiting: Vec<(Request, std::time::Instant)>,
+}
+
+impl LiveReload {
+    fn new(src_dir: &Path) -> Result<Self> {
+        if src_dir.join(LIVE_RELOAD_PATH).exists() {
+            eprintln!(
+                "[haystack] warning: {} is hidden by the live reload route /{}",
+                src_dir.join(LIVE_RELOAD_PATH).display(),
+                LIVE_RELOAD_PATH
+            );
+        }
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx).context("starting file watcher")?;
+        watcher
+            .watch(src_dir, notify::RecursiveMode::Recursive)
+            .with_context(|| format!("watching {}", src_dir.display()))?;
+        let started = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        Ok(LiveReload { _watcher: watcher, events: rx, started, changes: 0, pending: None, waiting: Vec::new() })
+    }
+
+    fn generation(&self) -> String {
+        format!("{:x}-{}", self.started, self.changes)
+    }
+
+    /// Takes in file events and, once they have settled for `WATCH_DEBOUNCE`,
+    /// moves to a new generation and answers every held poll. Also releases
+    /// polls held for `LIVE_RELOAD_HOLD`.
+    fn tick(&mut self) {
+        for event in self.events.try_iter() {
+            match event {
+                Ok(ev) if !matches!(ev.kind, notify::EventKind::Access(_)) => {
+                    self.pending = Some(std::time::Instant::now())
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("[haystack] watch error: {}", e),
+            }
+        }
+        if self.pending.is_some_and(|at| at.elapsed() >= WATCH_DEBOUNCE) {
+            self.pending = None;
+            self.changes += 1;
+            for (request, _) in std::mem::take(&mut self.waiting) {
+                self.answer(request);
+            }
+            return;
+        }
+        let (expired, waiting) =
+            std::mem::take(&mut self.waiting).into_iter().partition(|(_, at)| at.elapsed() >= LIVE_RELOAD_HOLD);
+        self.waiting = waiting;
+        for (request, _) in expired {
+            self.answer(request);
+        }
+    }
+
+    /// Answers a poll right away unless it is already at the current generation.
+    fn poll(&mut self, request: Request) {
+        let generation = self.generation();
+        let since = request.url().split_once('?').and_then(|(_, query)| {
+            query.split('&').find_map(|pair| pair.strip_prefix("since="))
+        });
+        if since == Some(generation.as_str()) {
+            self.waiting.push((request, std::time::Instant::now()));
+        } else {
+            self.answer(request);
+        }
+    }
+
+    fn answer(&self, request: Request) {
+        let resp = Response::from_string(self.generation())
+            .with_header(content_type_header("text/plain; charset=utf-8"))
+            .with_header(Header::from_bytes(&b"Cache-Control"[..], &b"no-store"[..]).unwrap());
+        let _ = request.respond(resp);
+    }
+}
+
+/// Whether an `Authorization` header carries the `--auth` credentials. The
+/// comparison takes the same time wherever the first difference is.
+fn basic_auth_matches(header: Option<&str>, expected: &str) -> bool {
+    let Some((scheme, token)) = header.and_then(|h| h.trim().split_once(' ')) else {
+        return false;
+    };
+    let token = token.trim().as_bytes();
+    let expected = expected.as_bytes();
+    let diff = token.iter().zip(expected).fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    scheme.eq_ignore_ascii_case("basic") && token.len() == expected.len() && diff == 0
+}
+
+/// Standard base64 with padding.
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char),
+                false => out.push('='),
             }
+        }
+    }
+    out
+}
+
+fn request_header<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str())
+}
+
+/// Responds with a static file, honoring a single-range `Range: bytes=...`
+/// header with `206 Partial Content` so media can be seeked. A whole textual
+/// file is compressed with `encoding`; ranges always address the plain bytes.
+fn serve_static(path: &Path, range: Option<&str>, encoding: Option<ContentEncoding>) -> Result<HttpResponse> {
+    let read = || -> std::io::Result<HttpResponse> {
+        let mut file = fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        let mime = content_type_for(path);
+        let content_type = content_type_header(&mime);
+        let accept_ranges = Header::from_bytes(&b"Accept-Ranges"[..], &b"bytes"[..]).unwrap();
+        let Some(range) = range else {
+            let mut bytes = Vec::with_capacity(len as usize);
+            file.read_to_end(&mut bytes)?;
+            return Ok(encoded_response(bytes, &mime, encoding).with_header(accept_ranges));
+        };
+        let Some((start, end)) = parse_byte_range(range, len) else {
+            let content_range = Header::from_bytes(&b"Content-Range"[..], format!("bytes */{}", len).as_bytes()).unwrap();
+            return Ok(Response::from_string("Range Not Satisfiable")
+                .with_status_code(416)
+                .with_header(content_range)
+                .with_header(accept_ranges));
         };
+        let mut bytes = vec![0; (end - start + 1) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut bytes)?;
+        let content_range =
+            Header::from_bytes(&b"Content-Range"[..], format!("bytes {}-{}/{}", start, end, len).as_bytes()).unwrap();
+        Ok(Response::from_data(bytes)
+            .with_status_code(206)
+            .with_header(content_type)
+            .with_header(content_range)
+            .with_header(accept_ranges))
+    };
+    read().with_context(|| format!("reading {}", path.display()))
+}
+
+/// A `Content-Encoding` `serve` can answer with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ContentEncoding {
+    Brotli,
+    Gzip,
+}
+
+impl ContentEncoding {
+    /// Best first: brotli is smaller than gzip at comparable speed.
+    const PREFERENCE: [ContentEncoding; 2] = [ContentEncoding::Brotli, ContentEncoding::Gzip];
+
+    /// Name in `Accept-Encoding` and `Content-Encoding`
+    fn token(self) -> &'static str {
+        match self {
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Gzip => "gzip",
+        }
+    }
+
+    /// Extension of a precompressed sibling (`app.js.br`)
+    fn extension(self) -> &'static str {
+        match self {
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Gzip => "gz",
+        }
+    }
+
+    /// Compresses in memory at a level meant for per-request use, not for
+    /// the smallest possible output.
+    fn encode(self, bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            ContentEncoding::Brotli => {
+                let params = brotli::enc::BrotliEncoderParams { quality: 5, ..Default::default() };
+                brotli::BrotliCompress(&mut &bytes[..], &mut out, &params).expect("compressing into memory");
+            }
+            ContentEncoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(&mut out, flate2::Compression::default());
+                encoder.write_all(bytes).and_then(|_| encoder.finish()).expect("compressing into memory");
+            }
+        }
+        out
+    }
+}
+
+/// Bodies smaller than this are sent as-is; compressing them gains little.
+const COMPRESS_MIN_BYTES: usize = 1024;
+
+/// The encodings an `Accept-Encoding` header allows, best first: by the
+/// client's q-values, then brotli before gzip.
+fn accepted_encodings(accept_encoding: Option<&str>) -> Vec<ContentEncoding> {
+    let Some(value) = accept_encoding else { return Vec::new() };
+    let mut accepted: Vec<(ContentEncoding, f32)> = ContentEncoding::PREFERENCE
+        .into_iter()
+        .map(|e| (e, coding_quality(value, e.token())))
+        .filter(|(_, q)| *q > 0.0)
+        .collect();
+    // Stable, so equal q-values keep the preference order
+    accepted.sort_by(|a, b| b.1.total_cmp(&a.1));
+    accepted.into_iter().map(|(e, _)| e).collect()
+}
+
+/// The q-value an `Accept-Encoding` header gives `coding`: its own entry if
+/// listed (so `br;q=0` refuses brotli even with `*`), else the `*` entry's,
+/// else 0. A missing or malformed `q` counts as 1.
+fn coding_quality(accept_encoding: &str, coding: &str) -> f32 {
+    let mut wildcard = None;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let listed = parts.next().unwrap_or("");
+        let q = parts
+            .find_map(|p| p.strip_prefix("q=").or_else(|| p.strip_prefix("Q=")))
+            .and_then(|q| q.trim().parse::<f32>().ok())
+            .map_or(1.0, |q| q.clamp(0.0, 1.0));
+        if listed.eq_ignore_ascii_case(coding) {
+            return q;
+        }
+        if listed == "*" {
+            wildcard = Some(q);
+        }
+    }
+    wildcard.unwrap_or(0.0)
+}
+
+/// Whether a Content-Type is text that compresses well (HTML, CSS, JS, JSON, SVG, ...).
+fn compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/") || ["javascript", "json", "xml"].iter().any(|t| content_type.contains(t))
+}
+
+/// A `200` with `body`, compressed with `encoding` when the content type is
+/// textual and the body is at least [`COMPRESS_MIN_BYTES`].
+fn encoded_response(body: Vec<u8>, content_type: &str, encoding: Option<ContentEncoding>) -> HttpResponse {
+    let encoding = encoding.filter(|_| body.len() >= COMPRESS_MIN_BYTES && compressible(content_type));
+    let Some(encoding) = encoding else {
+        return Response::from_data(body).with_header(content_type_header(content_type));
+    };
+    Response::from_data(encoding.encode(&body))
+        .with_header(content_type_header(content_type))
+        .with_header(Header::from_bytes(&b"Content-Encoding"[..], encoding.token().as_bytes()).unwrap())
+        .with_header(Header::from_bytes(&b"Vary"[..], &b"Accept-Encoding"[..]).unwrap())
+}
+
+/// `path` plus the `encoding`'s extension (`app.js.br`) if it exists and is at
+/// least as new as `path`; a stale sibling is ignored with a warning so edits
+/// to the original are never masked.
+fn fresh_sibling(path: &Path, encoding: ContentEncoding) -> Option<PathBuf> {
+    let mut sibling = path.as_os_str().to_owned();
+    sibling.push(".");
+    sibling.push(encoding.extension());
+    let sibling = PathBuf::from(sibling);
+    let sibling_modified = fs::metadata(&sibling).and_then(|m| m.modified()).ok()?;
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    if sibling_modified < modified {
+        eprintln!("[haystack] warning: {} is older than {}, ignoring it", sibling.display(), path.display());
+        return None;
+    }
+    Some(sibling)
+}
+
+/// Responds with the precompressed `file` for `path`, keeping `path`'s
+/// content type.
+fn serve_precompressed(path: &Path, file: &Path, encoding: ContentEncoding) -> Result<HttpResponse> {
+    let bytes = fs::read(file).with_context(|| format!("reading {}", file.display()))?;
+    Ok(Response::from_data(bytes)
+        .with_header(content_type_header(&content_type_for(path)))
+        .with_header(Header::from_bytes(&b"Content-Encoding"[..], encoding.token().as_bytes()).unwrap())
+        .with_header(Header::from_bytes(&b"Vary"[..], &b"Accept-Encoding"[..]).unwrap()))
+}
+
+/// An error response in the `--error-format` representation. `failure` is the
+/// file and error behind a 500 (logged here too): the response names the file
+/// and the error chain, plus the backtrace when `RUST_BACKTRACE` is set.
+fn error_response(
+    status: u16,
+    failure: Option<(&Path, &anyhow::Error)>,
+    format: ErrorFormat,
+    theme: &ThemeConfig,
+    opts: &RenderOptions,
+) -> HttpResponse {
+    let reason = tiny_http::StatusCode(status).default_reason_phrase();
+    if let Some((path, err)) = failure {
+        eprintln!("[haystack] {}: {:#}", path.display(), err);
+    }
+    let backtrace_on = std::env::var_os("RUST_BACKTRACE").is_some_and(|v| v != "0");
+    let backtrace = failure.filter(|_| backtrace_on).map(|(_, err)| err.backtrace().to_string());
+    let (body, content_type) = match format {
+        ErrorFormat::Text => {
+            let mut text = format!("{} {}\n", status, reason);
+            if let Some((path, err)) = failure {
+                text.push_str(&format!("{}: {:#}\n", path.display(), err));
+            }
+            if let Some(backtrace) = &backtrace {
+                text.push_str(&format!("\n{}\n", backtrace));
+            }
+            (text, TEXT_CONTENT_TYPE)
+        }
+        ErrorFormat::Json => {
+            let mut json = serde_json::json!({ "status": status, "error": reason });
+            if let Some((path, err)) = failure {
+                json["path"] = serde_json::Value::from(path.display().to_string());
+                json["message"] = serde_json::Value::from(format!("{:#}", err));
+            }
+            if let Some(backtrace) = backtrace {
+                json["backtrace"] = serde_json::Value::from(backtrace);
+            }
+            (json.to_string(), JSON_CONTENT_TYPE)
+        }
+        ErrorFormat::Html => {
+            let mut body = match failure {
+                Some((path, err)) => format!(
+                    "<h1>Error</h1>\n<p><code>{}</code></p>\n<pre class=\"error\">{}</pre>\n",
+                    escape_html(&path.display().to_string()),
+                    escape_html(&format!("{:#}", err))
+                ),
+                None => format!("<h1>{} {}</h1>\n", status, reason),
+            };
+            if let Some(backtrace) = &backtrace {
+                body.push_str(&format!(
+                    "<details open>\n<summary>Backtrace</summary>\n<pre>{}</pre>\n</details>\n",
+                    escape_html(backtrace)
+                ));
+            }
+            let meta = PageMeta {
+                title: Some(if failure.is_some() { "Error".to_string() } else { reason.to_string() }),
+                lang: opts.lang.clone().unwrap_or_else(|| "en".to_string()),
+                ..PageMeta::default()
+            };
+            (wrap_html_page(body, &meta, theme, opts, None), HTML_CONTENT_TYPE)
+        }
+    };
+    Response::from_string(body)
+        .with_status_code(status)
+        .with_header(content_type_header(content_type))
+}
+
+/// Parses `bytes=start-end`, `bytes=start-` or `bytes=-suffix` into an
+/// inclusive range clamped to `len`. Multiple ranges, malformed specs and
+/// ranges starting past the end yield `None` (416).
+fn parse_byte_range(header: &str, len: u64) -> Option<(u64, u64)> {
+    let spec = header.trim().strip_prefix("bytes=")?.trim();
+    if spec.contains(',') || len == 0 {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let (start, end) = if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 {
+            return None;
+        }
+        (len.saturating_sub(suffix), len - 1)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = if end.is_empty() { len - 1 } else { end.parse::<u64>().ok()?.min(len - 1) };
+        (start, end)
+    };
+    (start <= end && start < len).then_some((start, end))
+}
+
+const HTML_CONTENT_TYPE: &str = "text/html; charset=utf-8";
+const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+fn content_type_header(value: &str) -> Header {
+    Header::from_bytes(&b"Content-Type"[..], value.as_bytes()).expect("valid Content-Type header")
+}
+
+/// Guesses the Content-Type of a static file, declaring UTF-8 for textual types
+/// so they match the generated pages.
+fn content_type_for(path: &Path) -> String {
+    use mime_guess::mime;
+    let guessed = mime_guess::from_path(path).first_or_octet_stream();
+    let textual = guessed.type_() == mime::TEXT
+        || guessed.suffix() == Some(mime::XML)
+        || guessed.suffix() == Some(mime::JSON)
+        || matches!(guessed.subtype().as_str(), "javascript" | "json" | "xml");
+    if textual && guessed.get_param(mime::CHARSET).is_none() {
+        format!("{}; charset=utf-8", guessed)
+    } else {
+        guessed.to_string()
+    }
+}
+
+/// Renders a full page for `serve`. Also returns every file the result was
+/// built from (the source, its template, included files and, when it has
+/// prev/next links, its siblings) so a cached copy can be checked for changes.
+fn convert_file(path: &Path, src_dir: &Path, theme: &ThemeConfig, opts: &RenderOptions) -> Result<(String, Vec<PathBuf>)> {
+    let mut page = convert_file_body(path, opts)?;
+    let siblings = sibling_pages(path);
+    page.meta.nav = page_navs(&siblings).remove(path);
+    page.meta.url_path = Some(slash_path(&output_rel_path(path.strip_prefix(src_dir).unwrap_or(path))));
+    let template = find_template(src_dir, path);
+    let theme = scoped_theme(src_dir, path, theme)?;
+    let mut inputs = vec![path.to_path_buf()];
+    inputs.extend(template_path(src_dir, path));
+    inputs.extend(dir_config_paths(src_dir, path));
+    inputs.extend(page.meta.includes.iter().cloned());
+    if page.meta.nav.is_some() {
+        inputs.extend(siblings);
+    }
+    if opts.sidebar {
+        let pages: Vec<PathBuf> = source_files(src_dir, opts.follow_symlinks)
+            .into_iter()
+            .filter(|p| matches!(p.extension().and_then(|s| s.to_str()), Some("md" | "org")))
+            .collect();
+        let rel = path.strip_prefix(src_dir).unwrap_or(path);
+        page.meta.sidebar = Some(sidebar_html(&site_tree(src_dir, &pages), &output_rel_path(rel)));
+        inputs.extend(pages);
+    }
+    Ok((wrap_html_page(page.body, &page.meta, &theme, opts, template.as_deref()), inputs))
+}
 
-        let _ = request.respond(resp);
+/// `serve --cache`: rendered pages kept in memory, least recently used first
+/// out once `capacity` is reached.
+struct PageCache {
+    capacity: usize,
+    pages: HashMap<PathBuf, CachedPage>,
+    clock: u64,
+}
+
+struct CachedPage {
+    html: String,
+    /// Modification times of the files the page was built from, when rendered
+    inputs: Vec<(PathBuf, Option<SystemTime>)>,
+    last_used: u64,
+}
+
+impl PageCache {
+    fn new(capacity: usize) -> Self {
+        PageCache { capacity, pages: HashMap::new(), clock: 0 }
     }
 
-    Ok(())
+    /// The cached page for `path` if none of its inputs changed since it was
+    /// rendered, otherwise the result of `render` (which is then cached).
+    fn get_or_render(
+        &mut self,
+        path: &Path,
+        render: impl FnOnce() -> Result<(String, Vec<PathBuf>)>,
+    ) -> Result<String> {
+        let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+        self.clock += 1;
+        if let Some(page) = self.pages.get_mut(path) {
+            if page.inputs.iter().all(|(p, time)| modified(p) == *time) {
+                page.last_used = self.clock;
+                return Ok(page.html.clone());
+            }
+        }
+        let (html, inputs) = render()?;
+        if self.capacity == 0 {
+            return Ok(html);
+        }
+        if !self.pages.contains_key(path) && self.pages.len() >= self.capacity {
+            let oldest = self.pages.iter().min_by_key(|(_, page)| page.last_used).map(|(p, _)| p.clone());
+            if let Some(oldest) = oldest {
+                self.pages.remove(&oldest);
+            }
+        }
+        let inputs = inputs.into_iter().map(|p| {
+            let time = modified(&p);
+            (p, time)
+        });
+        let page = CachedPage { html: html.clone(), inputs: inputs.collect(), last_used: self.clock };
+        self.pages.insert(path.to_path_buf(), page);
+        Ok(html)
+    }
 }
 
-fn convert_file(path: &Path, theme: &ThemeConfig) -> Result<String> {
+/// Converts a source file to its page body and metadata, without the page layout.
+fn convert_file_body(path: &Path, opts: &RenderOptions) -> Result<ConvertedPage> {
     let mut file = fs::File::open(path)
         .with_context(|| format!("opening input file {}", path.display()))?;
     let mut buf = String::new();
     file.read_to_string(&mut buf)
         .with_context(|| format!("reading input file {}", path.display()))?;
+    let buf = normalize_source(buf);
+
+    let mut page = match path.extension().and_then(|s| s.to_str()) {
+        Some("md") => convert_markdown_to_html(&buf, path, opts)?,
+        Some("org") => convert_org_to_html(&buf, path, opts),
+        other => return Err(anyhow!("unsupported extension {:?} for {}", other, path.display())),
+    };
+    if opts.strip_comments {
+        page.body = strip_html_comments(&page.body);
+    }
+    page.meta.source_name = path.file_name().map(|n| n.to_string_lossy().into_owned());
+    Ok(page)
+}
+
+/// Removes HTML comments from a page body. Conditional comments
+/// (`<!--[if IE]>`, `<![endif]-->`) stay, and so does anything inside `<pre>`,
+/// `<code>`, `<script>`, `<style>` and `<textarea>`.
+fn strip_html_comments(html: &str) -> String {
+    static RE_COMMENT: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r"(?is)<pre\b.*?</pre\s*>|<code\b.*?</code\s*>|<script\b.*?</script\s*>|<style\b.*?</style\s*>|<textarea\b.*?</textarea\s*>|<!--(.*?)-->").unwrap()
+    });
+    RE_COMMENT
+        .replace_all(html, |caps: &regex::Captures| match caps.get(1).map(|m| m.as_str()) {
+            Some(text) if !text.starts_with("[if") && !text.starts_with("<![endif]") && !text.ends_with("<!") => String::new(),
+            _ => caps[0].to_string(),
+        })
+        .into_owned()
+}
+
+/// Drops a leading UTF-8 byte order mark and turns CRLF line endings into LF,
+/// so files saved on Windows parse like any other (front matter fences, `#+`
+/// keywords, code block lines).
+fn normalize_source(text: String) -> String {
+    let text = match text.strip_prefix('\u{feff}') {
+        Some(rest) => rest.to_string(),
+        None => text,
+    };
+    if text.contains('\r') { text.replace("\r\n", "\n") } else { text }
+}
 
-    match path.extension().and_then(|s| s.to_str()) {
-        Some("md") => Ok(convert_markdown_to_html(&buf, theme)),
-        Some("org") => Ok(convert_org_to_html(&buf, theme)),
-        other => Err(anyhow!("unsupported extension {:?} for {}", other, path.display())),
+/// Rewrites `:::details Title` ... `:::` containers (nestable, ignored inside
+/// fenced code) into raw `<details><summary>` lines followed by a blank line,
+/// so pulldown-cmark ends the HTML block there and still parses the lines
+/// inside as markdown. Unclosed containers are closed at the end with a warning.
+fn expand_details_blocks(input: &str, source: &Path) -> String {
+    static RE_OPEN: Lazy<Regex> = Lazy::new(|| Regex::new(r"^ {0,3}:{3,}\s*details(?:\s+(.*?))?\s*$").unwrap());
+    static RE_CLOSE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^ {0,3}:{3,}\s*$").unwrap());
+    static RE_FENCE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^ {0,3}(`{3,}|~{3,})").unwrap());
+    let mut out = String::with_capacity(input.len());
+    // Opening marker of the fenced code block we're in
+    let mut fence: Option<String> = None;
+    let mut depth = 0usize;
+    for line in input.split_inclusive('\n') {
+        let text = line.trim_end_matches(['\n', '\r']);
+        if let Some(marker) = &fence {
+            let closes = text.trim_start().starts_with(marker.as_str())
+                && text.trim().chars().all(|c| marker.starts_with(c));
+            if closes {
+                fence = None;
+            }
+        } else if let Some(m) = RE_FENCE.captures(text) {
+            fence = Some(m[1].to_string());
+        } else if let Some(caps) = RE_OPEN.captures(text) {
+            let title = caps.get(1).map_or("Details", |t| t.as_str());
+            out.push_str(&format!("<details>\n<summary>{}</summary>\n\n", escape_html(title)));
+            depth += 1;
+            continue;
+        } else if depth > 0 && RE_CLOSE.is_match(text) {
+            out.push_str("\n</details>\n");
+            depth -= 1;
+            continue;
+        }
+        out.push_str(line);
     }
+    if depth > 0 {
+        eprintln!("[haystack] {}: {} unclosed :::details block(s)", source.display(), depth);
+        for _ in 0..depth {
+            out.push_str("\n\n</details>\n");
+        }
+    }
+    out
 }
 
-fn convert_markdown_to_html(input: &str, theme: &ThemeConfig) -> String {
+fn markdown_options() -> Options {
     let mut options = Options::empty();
     options.insert(Options::ENABLE_TABLES);
     options.insert(Options::ENABLE_FOOTNOTES);
     options.insert(Options::ENABLE_STRIKETHROUGH);
     options.insert(Options::ENABLE_TASKLISTS);
+    options
+}
+
+fn convert_markdown_to_html(input: &str, source: &Path, opts: &RenderOptions) -> Result<ConvertedPage> {
+    let (front, input) = split_front_matter(input);
+    let expanded = expand_details_blocks(input, source);
+    let input = expanded.as_str();
+    let mut options = markdown_options();
+    if opts.math_mathml || opts.math {
+        options.insert(Options::ENABLE_MATH);
+    }
     let parser = MdParser::new_ext(input, options);
 
     // Transform code blocks into syntect-highlighted HTML
     let mut events = Vec::new();
     let mut in_code = false;
-    let mut code_lang: Option<String> = None;
+    let mut code_info = CodeInfo::default();
     let mut code_buf = String::new();
+    // Local SVG being inlined in place of an <img>, plus its alt text
+    let mut svg_inline: Option<(String, String)> = None;
+    let mut task_count = 0;
+    let mut includes: Vec<PathBuf> = Vec::new();
+    let mut first_image: Option<String> = None;
+    let mut has_math = false;
+    let mut has_mermaid = false;
+    // Destination and alt text so far of the image being read
+    let mut image_alt: Option<(String, String)> = None;
+    let mut unknown_languages = 0;
+    let mut empty_alts = 0;
 
     for ev in parser {
+        match &ev {
+            Event::Start(Tag::Image { dest_url, .. }) => image_alt = Some((dest_url.to_string(), String::new())),
+            Event::Text(t) | Event::Code(t) => {
+                if let Some((_, alt)) = image_alt.as_mut() {
+                    alt.push_str(t);
+                }
+            }
+            Event::End(TagEnd::Image) => {
+                if let Some((url, _)) = image_alt.take().filter(|(_, alt)| alt.trim().is_empty()) {
+                    eprintln!("[haystack] {}: image {} has no alt text", source.display(), url);
+                    empty_alts += 1;
+                }
+            }
+            _ => {}
+        }
         match ev {
+            Event::Start(Tag::Image { ref dest_url, .. }) if svg_inline.is_none() => {
+                if first_image.is_none() && is_preview_image(dest_url) {
+                    first_image = Some(dest_url.to_string());
+                }
+                let target = inline_svg_target(dest_url, opts.inline_svg);
+                let markup = target.and_then(|t| {
+                    let file = source.parent().unwrap_or(Path::new("")).join(t);
+                    let svg = fs::read_to_string(&file)
+                        .map_err(|e| eprintln!("[haystack] cannot inline {}: {}", file.display(), e))
+                        .ok();
+                    includes.push(file);
+                    svg
+                });
+                match markup {
+                    Some(svg) => svg_inline = Some((svg, String::new())),
+                    None => events.push(ev),
+                }
+            }
+            Event::Text(t) | Event::Code(t) if svg_inline.is_some() => {
+                if let Some((_, alt)) = svg_inline.as_mut() {
+                    alt.push_str(&t);
+                }
+            }
+            Event::End(TagEnd::Image) if svg_inline.is_some() => {
+                if let Some((svg, alt)) = svg_inline.take() {
+                    events.push(Event::Html(CowStr::from(sanitize_svg(&svg, &alt))));
+                }
+            }
+            _ if svg_inline.is_some() => {}
             Event::Start(Tag::CodeBlock(kind)) => {
                 in_code = true;
                 code_buf.clear();
-                code_lang = match kind {
+                code_info = match kind {
                     CodeBlockKind::Fenced(info) => {
-                        let first = info.split_whitespace().next().unwrap_or("");
-                        if first.is_empty() { None } else { Some(first.to_string()) }
+                        let mut info = parse_code_info(&info);
+                        if info.lang.is_none() {
+                            info.lang = opts.default_lang.clone();
+                        }
+                        info
                     }
-                    CodeBlockKind::Indented => None,
+                    CodeBlockKind::Indented => CodeInfo::default(),
                 };
             }
             Event::Text(t) if in_code => {
                 code_buf.push_str(&t);
             }
             Event::End(TagEnd::CodeBlock) => {
-                let html_snippet = highlight_code(&code_buf, code_lang.as_deref());
+                if let Some(file) = code_info.file.take() {
+                    let path = source.parent().unwrap_or(Path::new("")).join(&file);
+                    code_buf = fs::read_to_string(&path)
+                        .map(normalize_source)
+                        .with_context(|| format!("reading code file {} (```file:{})", path.display(), file))?;
+                    includes.push(path.clone());
+                    if let Ok(Some(syntax)) = SYNTAX_SET.find_syntax_for_file(&path) {
+                        let token = syntax.file_extensions.first().cloned();
+                        code_info.lang = token.or_else(|| Some(syntax.name.to_lowercase()));
+                    }
+                }
+                let delimiter = match code_info.lang.as_deref().map(str::to_ascii_lowercase).as_deref() {
+                    Some("csv") => Some(','),
+                    Some("tsv") => Some('\t'),
+                    _ => None,
+                };
+                let table = delimiter.and_then(|d| match delimited_table(&code_buf, d) {
+                    Ok(table) => Some(table),
+                    Err(e) => {
+                        eprintln!("[haystack] {}: {}; showing the data as code", source.display(), e);
+                        None
+                    }
+                });
+                if code_info.lang.as_deref().is_some_and(|l| unknown_code_language(l, source, opts)) {
+                    unknown_languages += 1;
+                }
+                let mermaid = opts.mermaid && code_info.lang.as_deref().is_some_and(|l| l.eq_ignore_ascii_case("mermaid"));
+                let html_snippet = if mermaid {
+                    has_mermaid = true;
+                    mermaid_html(&escape_html(&code_buf))
+                } else {
+                    table.unwrap_or_else(|| highlight_code(&code_buf, &code_info, opts))
+                };
                 events.push(Event::Html(CowStr::from(html_snippet)));
                 in_code = false;
-                code_lang = None;
+                code_info = CodeInfo::default();
+            }
+            Event::InlineMath(tex) => {
+                let (html, katex) = render_math(&tex, false, source, opts);
+                has_math |= katex;
+                events.push(Event::InlineHtml(CowStr::from(html)));
+            }
+            Event::DisplayMath(tex) => {
+                let (html, katex) = render_math(&tex, true, source, opts);
+                has_math |= katex;
+                events.push(Event::InlineHtml(CowStr::from(html)));
+            }
+            Event::TaskListMarker(checked) if opts.interactive_tasks => {
+                events.push(Event::InlineHtml(CowStr::from(format!(
+                    "<input type=\"checkbox\" class=\"task\" data-task=\"{}\"{}/>\n",
+                    task_count,
+                    if checked { " checked=\"\"" } else { "" }
+                ))));
+                task_count += 1;
+            }
+            Event::Html(h) if !in_code => {
+                events.push(Event::Html(CowStr::from(map_components(&h, &opts.mdx, source))));
+            }
+            Event::InlineHtml(h) if !in_code => {
+                events.push(Event::InlineHtml(CowStr::from(map_components(&h, &opts.mdx, source))));
+            }
+            // Wide tables scroll inside the wrapper instead of overflowing the page
+            Event::Start(Tag::Table(_)) if !opts.no_table_wrap => {
+                events.push(Event::Html(CowStr::from(TABLE_WRAP_START)));
+                events.push(ev);
+            }
+            Event::End(TagEnd::Table) if !opts.no_table_wrap => {
+                events.push(ev);
+                events.push(Event::Html(CowStr::from("</div>\n")));
             }
             other => {
                 if !in_code {
@@ -274,36 +3011,925 @@ fn convert_markdown_to_html(input: &str, theme: &ThemeConfig) -> String {
         }
     }
 
+    let events = if has_math { unwrap_display_math(events) } else { events };
+    let events = expand_embeds(events, source);
+    let (events, headings) = anchor_headings(events, opts.anchor_style);
+    let heading_skips = heading_level_skips(&headings);
+    for skip in &heading_skips {
+        eprintln!("[haystack] {}: {}", source.display(), skip);
+    }
+    let events = match &opts.toc {
+        Some(levels) => insert_toc(events, &headings, levels, opts.toc_min_headings),
+        None => events,
+    };
+    let events = if opts.figures { number_figures(events) } else { events };
+    let events = match opts.base_path.as_deref() {
+        Some(base) => events.into_iter().map(|ev| prefix_base_path(ev, base)).collect(),
+        None => events,
+    };
+    let events = match opts.external_links_new_tab {
+        true => open_external_links(events, opts.base_url.as_deref()),
+        false => events,
+    };
+
     let mut out = String::new();
     html::push_html(&mut out, events.into_iter());
-    let title = extract_title_from_markdown(input);
-    wrap_html_page(out, title, theme)
+    if let Some(sizes) = &opts.image_sizes {
+        out = sizes.annotate(&out, source, opts.base_path.as_deref());
+    }
+    let meta = PageMeta {
+        title: front.title.or_else(|| extract_title_from_markdown(input)),
+        description: front.description.or_else(|| page_excerpt(input, opts)),
+        date: front.date,
+        lang: front.lang.or_else(|| opts.lang.clone()).unwrap_or_else(|| "en".to_string()),
+        order: front.order,
+        has_tasks: task_count > 0,
+        has_math,
+        has_mermaid,
+        front_matter: front.fields,
+        includes,
+        image: front.image.or(first_image),
+        css: page_assets(&front.css, "css", source, opts.base_path.as_deref()),
+        js: page_assets(&front.js, "js", source, opts.base_path.as_deref()),
+        headings,
+        heading_skips: heading_skips.len(),
+        unknown_languages,
+        empty_alts,
+        ..PageMeta::default()
+    };
+    Ok(ConvertedPage { body: out, meta })
+}
+
+const TABLE_WRAP_START: &str = "<div class=\"table-wrap\" style=\"overflow-x:auto\">\n";
+
+const MERMAID_START: &str = "<pre class=\"mermaid\">";
+
+/// A `--mermaid` diagram: the block's source (already HTML-escaped), left for
+/// mermaid.js to draw.
+fn mermaid_html(source_escaped: &str) -> String {
+    format!("{}{}</pre>\n", MERMAID_START, source_escaped)
+}
+
+const KATEX_DISPLAY_START: &str = "<div class=\"math display\">";
+
+/// Drops the paragraph around `--math` display formulas that stand alone, so
+/// their `<div>` isn't nested in a `<p>`.
+fn unwrap_display_math(events: Vec<Event<'_>>) -> Vec<Event<'_>> {
+    let mut out: Vec<Event> = Vec::with_capacity(events.len());
+    for ev in events {
+        let alone = matches!(ev, Event::End(TagEnd::Paragraph))
+            && matches!(out.as_slice(), [.., Event::Start(Tag::Paragraph), Event::InlineHtml(h)] if h.starts_with(KATEX_DISPLAY_START));
+        if alone {
+            let math = out.pop();
+            out.pop();
+            out.extend(math);
+        } else {
+            out.push(ev);
+        }
+    }
+    out
+}
+
+/// Markup for a `$...$` (or, with `display`, `$$...$$`) LaTeX snippet, and
+/// whether it needs KaTeX. `--math-mathml` converts it here; what the
+/// converter cannot handle is left to KaTeX when `--math` is also given, and
+/// otherwise shown as literal `<code class="math">`.
+fn render_math(tex: &str, display: bool, source: &Path, opts: &RenderOptions) -> (String, bool) {
+    if opts.math_mathml {
+        match math_to_mathml(tex, display) {
+            Ok(mathml) => return (mathml, false),
+            Err(e) if opts.math => {
+                eprintln!("[haystack] {}: cannot convert math {:?} to MathML: {}; leaving it to KaTeX", source.display(), tex, e);
+            }
+            Err(e) => {
+                eprintln!("[haystack] {}: cannot convert math {:?} to MathML: {}", source.display(), tex, e);
+                let class = if display { "math math-display" } else { "math" };
+                return (format!("<code class=\"{}\">{}</code>", class, escape_html(tex)), false);
+            }
+        }
+    }
+    let html = match display {
+        true => format!("{}\\[{}\\]</div>\n", KATEX_DISPLAY_START, escape_html(tex)),
+        false => format!("<span class=\"math inline\">\\({}\\)</span>", escape_html(tex)),
+    };
+    (html, true)
+}
+
+fn math_to_mathml(tex: &str, display: bool) -> std::result::Result<String, String> {
+    let style = if display { DisplayStyle::Block } else { DisplayStyle::Inline };
+    // Some malformed input comes back as MathML with the error spliced in
+    latex_to_mathml(tex, style).map_err(|e| e.to_string()).and_then(|mathml| match mathml.find("[PARSE ERROR") {
+        Some(at) => Err(mathml[at..].split("</").next().unwrap_or_default().to_string()),
+        None => Ok(mathml),
+    })
+}
+
+/// Renders CSV/TSV text as a `<table>` whose first row is the header. Fields
+/// may be `"quoted"` (with `""` for a literal quote) to contain the delimiter or
+/// line breaks. Every row must have as many fields as the header.
+fn delimited_table(data: &str, delimiter: char) -> Result<String> {
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    let mut row: Vec<String> = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = data.trim_end_matches(['\n', '\r']).chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' if in_quotes => in_quotes = false,
+            '"' if field.is_empty() => in_quotes = true,
+            '\r' if !in_quotes => {}
+            '\n' if !in_quotes => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            c if c == delimiter && !in_quotes => row.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if in_quotes {
+        return Err(anyhow!("unterminated quoted field"));
+    }
+    row.push(field);
+    rows.push(row);
+    let mut rows = rows.into_iter().filter(|r| !(r.len() == 1 && r[0].trim().is_empty()));
+    let header = rows.next().ok_or_else(|| anyhow!("empty table"))?;
+    let cell_row = |cells: &[String], tag: &str| -> String {
+        let cells: String = cells.iter().map(|c| format!("<{0}>{1}</{0}>", tag, escape_html(c.trim()))).collect();
+        format!("<tr>{}</tr>\n", cells)
+    };
+    let mut html = format!("<table class=\"data-table\">\n<thead>\n{}</thead>\n<tbody>\n", cell_row(&header, "th"));
+    for (i, row) in rows.enumerate() {
+        if row.len() != header.len() {
+            return Err(anyhow!("row {} has {} fields, expected {}", i + 2, row.len(), header.len()));
+        }
+        html.push_str(&cell_row(&row, "td"));
+    }
+    html.push_str("</tbody>\n</table>\n");
+    Ok(html)
+}
+
+/// Rewrites JSX-style component tags (capitalized names) in raw HTML according
+/// to the `[mdx]` config: mapped components become their configured element,
+/// unmapped ones are kept, stripped or warned about.
+fn map_components(html: &str, mdx: &MdxConfig, source: &Path) -> String {
+    static RE_COMPONENT: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"<(/?)([A-Z][A-Za-z0-9_.]*)(\s[^<>]*?)?\s*(/?)>").unwrap());
+    if mdx.components.is_empty() && mdx.unmapped == UnmappedComponents::Keep {
+        return html.to_string();
+    }
+    RE_COMPONENT
+        .replace_all(html, |caps: &regex::Captures| {
+            let closing = !caps[1].is_empty();
+            let name = &caps[2];
+            let attrs = caps.get(3).map(|m| m.as_str()).unwrap_or("");
+            let self_closing = !caps[4].is_empty();
+            let Some(spec) = mdx.components.get(name) else {
+                return match mdx.unmapped {
+                    UnmappedComponents::Strip => String::new(),
+                    UnmappedComponents::Keep => caps[0].to_string(),
+                    UnmappedComponents::Warn => {
+                        if !closing {
+                            eprintln!("[haystack] {}: unmapped component <{}>", source.display(), name);
+                        }
+                        caps[0].to_string()
+                    }
+                };
+            };
+            let mut parts = spec.split('.');
+            let tag = match parts.next() {
+                Some(t) if !t.is_empty() => t,
+                _ => "div",
+            };
+            let classes = parts.filter(|c| !c.is_empty()).collect::<Vec<_>>().join(" ");
+            if closing {
+                return format!("</{}>", tag);
+            }
+            let class_attr = if classes.is_empty() { String::new() } else { format!(" class=\"{}\"", classes) };
+            let open = format!("<{}{}{}>", tag, class_attr, attrs);
+            if self_closing { format!("{}</{}>", open, tag) } else { open }
+        })
+        .into_owned()
+}
+
+/// `docs`, `/docs/` → `/docs`; empty or `/` → `None`.
+fn normalize_base_path(path: &str) -> Option<String> {
+    let trimmed = path.trim().trim_matches('/');
+    if trimmed.is_empty() { None } else { Some(format!("/{}", trimmed)) }
+}
+
+/// Prefixes root-relative (`/...`, but not protocol-relative `//...`) link and
+/// image URLs with the deploy base path. Relative and absolute URLs are untouched.
+fn prefix_base_path<'a>(ev: Event<'a>, base: &str) -> Event<'a> {
+    let rebase = |url: CowStr<'a>| -> CowStr<'a> {
+        if url.starts_with('/') && !url.starts_with("//") {
+            CowStr::from(format!("{}{}", base, url))
+        } else {
+            url
+        }
+    };
+    match ev {
+        Event::Start(Tag::Link { link_type, dest_url, title, id }) => {
+            Event::Start(Tag::Link { link_type, dest_url: rebase(dest_url), title, id })
+        }
+        Event::Start(Tag::Image { link_type, dest_url, title, id }) => {
+            Event::Start(Tag::Image { link_type, dest_url: rebase(dest_url), title, id })
+        }
+        other => other,
+    }
+}
+
+/// Attributes added to external links by `--external-links-new-tab`.
+const EXTERNAL_LINK_ATTRS: &str = " class=\"external\" target=\"_blank\" rel=\"noopener noreferrer\"";
+
+/// Whether `url` is an `http(s)` link to a host other than `base_url`'s.
+fn is_external_link(url: &str, base_url: Option<&str>) -> bool {
+    let host = |url: &str| -> Option<String> {
+        let (scheme, rest) = url.split_once("://")?;
+        if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+            return None;
+        }
+        let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+        Some(authority.rsplit('@').next().unwrap_or("").to_lowercase())
+    };
+    match host(url) {
+        Some(link_host) => base_url.and_then(host).is_none_or(|base_host| base_host != link_host),
+        None => false,
+    }
+}
+
+/// Rewrites external links as raw `<a>` tags carrying `EXTERNAL_LINK_ATTRS`,
+/// which pulldown-cmark's own links can't. Other links are untouched.
+fn open_external_links<'a>(events: Vec<Event<'a>>, base_url: Option<&str>) -> Vec<Event<'a>> {
+    let mut in_external = false;
+    events
+        .into_iter()
+        .map(|ev| match ev {
+            Event::Start(Tag::Link { ref dest_url, ref title, .. }) if is_external_link(dest_url, base_url) => {
+                in_external = true;
+                let title = match title.is_empty() {
+                    true => String::new(),
+                    false => format!(" title=\"{}\"", escape_html(title)),
+                };
+                Event::InlineHtml(CowStr::from(format!("<a href=\"{}\"{}{}>", escape_html(dest_url), title, EXTERNAL_LINK_ATTRS)))
+            }
+            Event::End(TagEnd::Link) if in_external => {
+                in_external = false;
+                Event::InlineHtml(CowStr::from("</a>"))
+            }
+            other => other,
+        })
+        .collect()
+}
+
+/// `open_external_links` for org output, where links are already HTML.
+fn open_external_links_in_html(html: &str, base_url: Option<&str>) -> String {
+    static RE_LINK: Lazy<Regex> = Lazy::new(|| Regex::new(r#"<a href="([^"]*)">"#).unwrap());
+    RE_LINK
+        .replace_all(html, |caps: &regex::Captures| {
+            let url = caps[1].replace("&amp;", "&");
+            match is_external_link(&url, base_url) {
+                true => format!("<a href=\"{}\"{}>", &caps[1], EXTERNAL_LINK_ATTRS),
+                false => caps[0].to_string(),
+            }
+        })
+        .into_owned()
+}
+
+/// Turns paragraphs consisting of a single image with alt text into
+/// `<figure id="figure-N">` with a "Figure N: alt" caption, numbered per page.
+/// Images without alt text or mixed with other inline content are left alone.
+fn number_figures(events: Vec<Event<'_>>) -> Vec<Event<'_>> {
+    let mut out = Vec::with_capacity(events.len());
+    let mut count = 0;
+    let mut i = 0;
+    while i < events.len() {
+        if let (Event::Start(Tag::Paragraph), Some(Event::Start(Tag::Image { .. }))) = (&events[i], events.get(i + 1)) {
+            let image_end = events[i + 1..].iter().position(|e| matches!(e, Event::End(TagEnd::Image))).map(|p| i + 1 + p);
+            if let Some(end) = image_end {
+                if matches!(events.get(end + 1), Some(Event::End(TagEnd::Paragraph))) {
+                    let alt: String = events[i + 2..end]
+                        .iter()
+                        .filter_map(|e| match e {
+                            Event::Text(t) | Event::Code(t) => Some(t.as_ref()),
+                            _ => None,
+                        })
+                        .collect();
+                    if !alt.trim().is_empty() {
+                        count += 1;
+                        out.push(Event::Html(CowStr::from(format!("<figure id=\"figure-{}\">\n", count))));
+                        out.extend(events[i + 1..=end].iter().cloned());
+                        out.push(Event::Html(CowStr::from(format!(
+                            "\n<figcaption>Figure {}: {}</figcaption>\n</figure>\n",
+                            count,
+                            escape_html(alt.trim())
+                        ))));
+                        i = end + 2;
+                        continue;
+                    }
+                }
+            }
+        }
+        out.push(events[i].clone());
+        i += 1;
+    }
+    out
+}
+
+/// Replaces paragraphs consisting only of an embed directive, `:youtube[ID]`,
+/// `:vimeo[ID]` or `:codepen[USER/PEN]`, with the provider's responsive
+/// `<iframe>`. Directives with an unknown name or a malformed id are left as
+/// text, with a warning.
+fn expand_embeds<'a>(events: Vec<Event<'a>>, source: &Path) -> Vec<Event<'a>> {
+    static RE_DIRECTIVE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^:([A-Za-z][\w-]*)\[([^\]]*)\]$").unwrap());
+    let mut out = Vec::with_capacity(events.len());
+    let mut i = 0;
+    while i < events.len() {
+        if let Event::Start(Tag::Paragraph) = events[i] {
+            // pulldown-cmark may split the text at the brackets
+            let texts = events[i + 1..].iter().take_while(|e| matches!(e, Event::Text(_))).count();
+            if matches!(events.get(i + 1 + texts), Some(Event::End(TagEnd::Paragraph))) {
+                let text: String = events[i + 1..i + 1 + texts]
+                    .iter()
+                    .filter_map(|e| match e {
+                        Event::Text(t) => Some(t.as_ref()),
+                        _ => None,
+                    })
+                    .collect();
+                if let Some(caps) = RE_DIRECTIVE.captures(text.trim()) {
+                    match embed_html(&caps[1], caps[2].trim()) {
+                        Ok(html) => {
+                            out.push(Event::Html(CowStr::from(html)));
+                            i += texts + 2;
+                            continue;
+                        }
+                        Err(e) => eprintln!("[haystack] {}: {}: {}, left as text", source.display(), text.trim(), e),
+                    }
+                }
+            }
+        }
+        out.push(events[i].clone());
+        i += 1;
+    }
+    out
+}
+
+/// The `div.embed` markup for an embed directive.
+fn embed_html(provider: &str, id: &str) -> Result<String, String> {
+    static RE_ID: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[A-Za-z0-9_-]+$").unwrap());
+    let valid = |part: &str| RE_ID.is_match(part);
+    let (kind, src, title) = match provider.to_ascii_lowercase().as_str() {
+        "youtube" if valid(id) => ("youtube", format!("https://www.youtube-nocookie.com/embed/{}", id), "YouTube video"),
+        "vimeo" if id.chars().all(|c| c.is_ascii_digit()) && !id.is_empty() => {
+            ("vimeo", format!("https://player.vimeo.com/video/{}", id), "Vimeo video")
+        }
+        "codepen" => match id.split_once('/') {
+            Some((user, pen)) if valid(user) && valid(pen) => {
+                ("codepen", format!("https://codepen.io/{}/embed/{}?default-tab=result", user, pen), "CodePen")
+            }
+            _ => return Err("expected :codepen[USER/PEN]".to_string()),
+        },
+        "youtube" | "vimeo" => return Err(format!("invalid {} id {:?}", provider, id)),
+        _ => return Err(format!("unknown embed {:?} (known: youtube, vimeo, codepen)", provider)),
+    };
+    Ok(format!(
+        "<div class=\"embed embed-{}\"><iframe src=\"{}\" title=\"{}\" loading=\"lazy\" allow=\"autoplay; encrypted-media; fullscreen; picture-in-picture\" allowfullscreen></iframe></div>\n",
+        kind, src, title
+    ))
+}
+
+/// Gives every heading a unique slug `id` and, depending on `style`, a visible
+/// anchor link to itself.
+fn anchor_headings(events: Vec<Event<'_>>, style: AnchorStyle) -> (Vec<Event<'_>>, Vec<TocEntry>) {
+    let mut out = Vec::with_capacity(events.len());
+    let mut headings = Vec::new();
+    let mut slugger = Slugger::default();
+    let mut heading: Option<(Tag, Vec<Event>)> = None;
+    for ev in events {
+        match ev {
+            Event::Start(tag @ Tag::Heading { .. }) => heading = Some((tag, Vec::new())),
+            Event::End(TagEnd::Heading(level)) => {
+                let Some((tag, inner)) = heading.take() else { continue };
+                let Tag::Heading { id, classes, attrs, .. } = tag else { continue };
+                let text: String = inner
+                    .iter()
+                    .filter_map(|e| match e {
+                        Event::Text(t) | Event::Code(t) => Some(t.as_ref()),
+                        _ => None,
+                    })
+                    .collect();
+                let slug = match id {
+                    Some(id) => id.to_string(),
+                    None => slugger.slug(&text),
+                };
+                headings.push(TocEntry { level: level as u8, id: slug.clone(), text: text.trim().to_string() });
+                out.push(Event::Start(Tag::Heading {
+                    level,
+                    id: Some(CowStr::from(slug.clone())),
+                    classes,
+                    attrs,
+                }));
+                if style == AnchorStyle::Hash {
+                    out.push(Event::Html(CowStr::from(anchor_link_html(&slug, style))));
+                }
+                out.extend(inner);
+                if style == AnchorStyle::Icon {
+                    out.push(Event::Html(CowStr::from(anchor_link_html(&slug, style))));
+                }
+                out.push(Event::End(TagEnd::Heading(level)));
+            }
+            other => match heading.as_mut() {
+                Some((_, inner)) => inner.push(other),
+                None => out.push(other),
+            },
+        }
+    }
+    (out, headings)
+}
+
+/// A warning for every heading more than one level deeper than the heading
+/// before it (`h2` then `h4`). Going back up by any number of levels is fine.
+fn heading_level_skips(headings: &[TocEntry]) -> Vec<String> {
+    headings
+        .windows(2)
+        .filter(|pair| pair[1].level > pair[0].level + 1)
+        .map(|pair| {
+            format!(
+                "heading \"{}\" (h{}) skips a level after \"{}\" (h{})",
+                pair[1].text, pair[1].level, pair[0].text, pair[0].level
+            )
+        })
+        .collect()
+}
+
+/// A heading as listed in the table of contents and the `--outline`.
+#[derive(Serialize, Debug, Clone)]
+struct TocEntry {
+    level: u8,
+    id: String,
+    text: String,
+}
+
+/// Puts the table of contents after the page's leading `h1` (or first, when
+/// the page doesn't start with one). Pages with fewer than `min_headings`
+/// headings in `levels` (and so any without one) get none.
+fn insert_toc<'a>(
+    mut events: Vec<Event<'a>>,
+    headings: &[TocEntry],
+    levels: &std::ops::RangeInclusive<u8>,
+    min_headings: usize,
+) -> Vec<Event<'a>> {
+    let entries: Vec<&TocEntry> = headings.iter().filter(|h| levels.contains(&h.level)).collect();
+    if entries.is_empty() || entries.len() < min_headings {
+        return events;
+    }
+    let first_heading = events.iter().position(|e| matches!(e, Event::Start(Tag::Heading { .. })));
+    let at = match first_heading {
+        Some(i) if matches!(events[i], Event::Start(Tag::Heading { level: HeadingLevel::H1, .. })) => events
+            .iter()
+            .skip(i)
+            .position(|e| matches!(e, Event::End(TagEnd::Heading(_))))
+            .map_or(0, |end| i + end + 1),
+        _ => 0,
+    };
+    events.insert(at, Event::Html(CowStr::from(toc_html(&entries))));
+    events
+}
+
+/// Nested `<ul>`s of heading links. A heading deeper than the one before opens
+/// a sublist (however many levels it skips); a shallower one closes sublists
+/// back to the nearest level at or above it.
+fn toc_html(entries: &[&TocEntry]) -> String {
+    let mut html = String::from("<nav class=\"toc\" aria-label=\"Contents\">\n");
+    // Level of each open list, innermost last; every open list has an open <li>
+    let mut open: Vec<u8> = Vec::new();
+    for entry in entries {
+        match open.last().copied() {
+            None => {
+                html.push_str("<ul>\n<li>");
+                open.push(entry.level);
+            }
+            Some(top) if entry.level > top => {
+                html.push_str("\n<ul>\n<li>");
+                open.push(entry.level);
+            }
+            Some(_) => {
+                while open.len() > 1 && entry.level < open[open.len() - 1] && entry.level <= open[open.len() - 2] {
+                    open.pop();
+                    html.push_str("</li>\n</ul>\n");
+                }
+                html.push_str("</li>\n<li>");
+                if let Some(top) = open.last_mut() {
+                    *top = (*top).min(entry.level);
+                }
+            }
+        }
+        html.push_str(&format!("<a href=\"#{}\">{}</a>", escape_html(&entry.id), escape_html(&entry.text)));
+    }
+    for _ in &open {
+        html.push_str("</li>\n</ul>\n");
+    }
+    html.push_str("</nav>\n");
+    html
+}
+
+fn anchor_link_html(slug: &str, style: AnchorStyle) -> String {
+    match style {
+        AnchorStyle::None => String::new(),
+        AnchorStyle::Hash => format!(
+            "<a class=\"anchor anchor-hash\" href=\"#{}\" aria-hidden=\"true\">#</a>",
+            slug
+        ),
+        AnchorStyle::Icon => format!(
+            "<a class=\"anchor anchor-icon\" href=\"#{}\" aria-label=\"Link to this section\"><svg viewBox=\"0 0 24 24\" width=\"0.8em\" height=\"0.8em\" fill=\"none\" stroke=\"currentColor\" stroke-width=\"2\" stroke-linecap=\"round\" stroke-linejoin=\"round\" aria-hidden=\"true\"><path d=\"M10 13a5 5 0 0 0 7.54.54l3-3a5 5 0 0 0-7.07-7.07l-1.72 1.71\"/><path d=\"M14 11a5 5 0 0 0-7.54-.54l-3 3a5 5 0 0 0 7.07 7.07l1.71-1.71\"/></svg></a>",
+            slug
+        ),
+    }
+}
+
+/// Turns heading text into `id`s, de-duplicating repeats within one document
+/// with a numeric suffix (`intro`, `intro-1`, ...).
+#[derive(Debug, Default)]
+struct Slugger {
+    used: HashSet<String>,
+}
+
+impl Slugger {
+    fn slug(&mut self, text: &str) -> String {
+        let base = slugify(text);
+        let mut slug = base.clone();
+        let mut n = 0;
+        while !self.used.insert(slug.clone()) {
+            n += 1;
+            slug = format!("{}-{}", base, n);
+        }
+        slug
+    }
+}
+
+/// Lowercases, transliterates accented Latin letters to ASCII (`é` → `e`,
+/// `ß` → `ss`), turns whitespace into hyphens and strips everything else.
+/// Text with nothing left to keep (CJK, emoji) gets a stable `h-` + hash id.
+fn slugify(text: &str) -> String {
+    let text = text.trim();
+    let mut slug = String::new();
+    for c in text.nfd().filter(|c| !is_combining_mark(*c)) {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if let Some(ascii) = transliterate(c) {
+            slug.push_str(ascii);
+        } else if (c.is_whitespace() || c == '-' || c == '_') && !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_end_matches('-');
+    if !slug.is_empty() {
+        slug.to_string()
+    } else if text.is_empty() {
+        "section".to_string()
+    } else {
+        // Fixed algorithm: ids must not change with `build --hash`
+        format!("h-{}", &content_hash(HashAlgorithm::Sha256, text.as_bytes())[..8])
+    }
+}
+
+/// ASCII spelling of Latin letters that don't decompose into a base letter
+/// plus accents.
+fn transliterate(c: char) -> Option<&'static str> {
+    Some(match c {
+        'ß' => "ss",
+        'æ' | 'Æ' => "ae",
+        'œ' | 'Œ' => "oe",
+        'ø' | 'Ø' => "o",
+        'đ' | 'Đ' | 'ð' | 'Ð' => "d",
+        'ł' | 'Ł' => "l",
+        'þ' | 'Þ' => "th",
+        'ı' => "i",
+        _ => return None,
+    })
 }
 
 // Minimal Org-mode to HTML converter: supports headings, lists, paragraphs.
-fn convert_org_to_html(input: &str, theme: &ThemeConfig) -> String {
+fn convert_org_to_html(input: &str, source: &Path, opts: &RenderOptions) -> ConvertedPage {
     let org = Org::parse(input);
+    let mut handler = OrgHtmlHandler {
+        captions: org_captions(&org, source),
+        number_figures: opts.figures,
+        anchor_style: opts.anchor_style,
+        ..OrgHtmlHandler::default()
+    };
     let mut bytes: Vec<u8> = Vec::new();
-    let _ = org.write_html(&mut bytes);
+    let _ = org.write_html_custom(&mut bytes, &mut handler);
     let body = String::from_utf8(bytes).unwrap_or_default();
-    let title = extract_title_from_org(input);
-    let body = highlight_code_blocks_in_html(&body);
-    wrap_html_page(body, title, theme)
+    let (body, unknown_languages) = highlight_code_blocks_in_html(&body, source, opts);
+    let (body, task_count) = org_checkboxes(&body, opts.interactive_tasks);
+    let body = match opts.external_links_new_tab {
+        true => open_external_links_in_html(&body, opts.base_url.as_deref()),
+        false => body,
+    };
+    let meta = PageMeta {
+        title: extract_title_from_org(input),
+        description: org_keyword(input, "description").map(str::to_string),
+        date: org_keyword(input, "date").map(str::to_string),
+        lang: org_keyword(input, "language").or(opts.lang.as_deref()).unwrap_or("en").to_string(),
+        order: org_order(input),
+        has_tasks: opts.interactive_tasks && task_count > 0,
+        has_mermaid: opts.mermaid && body.contains(MERMAID_START),
+        unknown_languages,
+        ..PageMeta::default()
+    };
+    ConvertedPage { body, meta }
+}
+
+/// orgize's HTML output, except statistics cookies (`[2/5]`, `[40%]`) become
+/// progress badges instead of `<code>`, image links become `<img>`s,
+/// `#+CAPTION:`ed images and tables become `<figure>`s and headlines get ids
+/// and anchor links like markdown headings.
+#[derive(Default)]
+struct OrgHtmlHandler {
+    default: DefaultHtmlHandler,
+    slugger: Slugger,
+    anchor_style: AnchorStyle,
+    /// Anchor of each headline not yet closed, innermost last
+    open_headlines: Vec<String>,
+    /// From `org_captions`, consumed as their elements start
+    captions: HashMap<usize, OrgCaption>,
+    /// Number image figures and give them ids, as `--figures` does for markdown
+    number_figures: bool,
+    figure_count: usize,
+    /// `Start` events handled so far
+    started: usize,
+    /// Elements started and not yet ended
+    depth: usize,
+    /// Captioned elements not yet closed: their depth and caption
+    open_figures: Vec<(usize, OrgCaption)>,
+}
+
+/// A `#+CAPTION:` value as HTML, and what it captions.
+#[derive(Debug, Clone)]
+struct OrgCaption {
+    html: String,
+    image: bool,
+}
+
+impl HtmlHandler<std::io::Error> for OrgHtmlHandler {
+    fn start<W: std::io::Write>(&mut self, mut w: W, element: &Element) -> std::io::Result<()> {
+        let index = self.started;
+        self.started += 1;
+        let caption = self.captions.remove(&index);
+        if let Some(mut caption) = caption.clone() {
+            if caption.image && self.number_figures {
+                self.figure_count += 1;
+                writeln!(w, "<figure id=\"figure-{}\">", self.figure_count)?;
+                caption.html = format!("Figure {}: {}", self.figure_count, caption.html);
+            } else {
+                writeln!(w, "<figure>")?;
+            }
+            self.open_figures.push((self.depth, caption));
+        }
+        self.depth += 1;
+        match element {
+            // The image sits in the figure directly, as in markdown figures
+            Element::Paragraph { .. } if caption.is_some_and(|c| c.image) => Ok(()),
+            Element::Link(link) if org_image_src(link).is_some() => {
+                let src = org_image_src(link).unwrap_or_default();
+                let alt = src.rsplit('/').next().unwrap_or(src);
+                write!(w, "<img src=\"{}\" alt=\"{}\">", escape_html(src), escape_html(alt))
+            }
+            Element::Title(title) => {
+                let slug = match title.properties.iter().find(|(k, _)| k.eq_ignore_ascii_case("custom_id")) {
+                    Some((_, id)) => id.trim().to_string(),
+                    None => self.slugger.slug(&org_plain_text(&title.raw)),
+                };
+                write!(w, "<h{} id=\"{}\">", title.level.min(6), escape_html(&slug))?;
+                if self.anchor_style == AnchorStyle::Hash {
+                    write!(w, "{}", anchor_link_html(&slug, self.anchor_style))?;
+                }
+                self.open_headlines.push(slug);
+                Ok(())
+            }
+            Element::Cookie(cookie) => {
+                let value = cookie.value.trim_matches(|c| c == '[' || c == ']');
+                let complete = match value.split_once('/') {
+                    Some((done, total)) => done == total && !total.is_empty(),
+                    None => value == "100%",
+                };
+                write!(
+                    w,
+                    "<span class=\"progress-cookie{}\">{}</span>",
+                    if complete { " complete" } else { "" },
+                    escape_html(value)
+                )
+            }
+            Element::SpecialBlock(block) if admonition_kind(&block.name).is_some() => {
+                let kind = admonition_kind(&block.name).unwrap_or_default();
+                let title = block.parameters.as_deref().map(str::trim).filter(|t| !t.is_empty());
+                write!(w, "{}", admonition_start_html(kind, title))
+            }
+            _ => self.default.start(w, element),
+        }
+    }
+
+    fn end<W: std::io::Write>(&mut self, mut w: W, element: &Element) -> std::io::Result<()> {
+        self.depth -= 1;
+        let figure = match self.open_figures.last() {
+            Some((depth, _)) if *depth == self.depth => self.open_figures.pop().map(|(_, caption)| caption),
+            _ => None,
+        };
+        match element {
+            Element::SpecialBlock(block) if admonition_kind(&block.name).is_some() => write!(w, "</div>")?,
+            Element::Title(title) => {
+                let slug = self.open_headlines.pop().unwrap_or_default();
+                if self.anchor_style == AnchorStyle::Icon {
+                    write!(w, "{}", anchor_link_html(&slug, self.anchor_style))?;
+                }
+                write!(w, "</h{}>", title.level.min(6))?;
+            }
+            Element::Paragraph { .. } if figure.as_ref().is_some_and(|c| c.image) => {}
+            _ => self.default.end(&mut w, element)?,
+        }
+        match figure {
+            Some(caption) => write!(w, "\n<figcaption>{}</figcaption>\n</figure>\n", caption.html),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Image extensions Org's HTML export shows inline.
+const ORG_IMAGE_EXTENSIONS: [&str; 8] = ["png", "jpg", "jpeg", "gif", "webp", "svg", "avif", "bmp"];
+
+/// The image a link without description points to (`[[file:chart.png]]`,
+/// `[[./chart.png]]`); such links render as `<img>`, not `<a>`.
+fn org_image_src<'a>(link: &'a Link) -> Option<&'a str> {
+    if link.desc.is_some() {
+        return None;
+    }
+    let src = link.path.strip_prefix("file:").unwrap_or(&link.path);
+    let ext = Path::new(src.split(['?', '#']).next()?).extension()?.to_str()?;
+    ORG_IMAGE_EXTENSIONS.iter().any(|e| e.eq_ignore_ascii_case(ext)).then_some(src)
+}
+
+/// Captions for the elements right below a `#+CAPTION:` line, keyed by the
+/// index of the element's `Start` event in `org.iter()` (the order
+/// `OrgHtmlHandler` sees them). Only an image alone in its paragraph or a
+/// table can be captioned; any other caption is dropped with a warning.
+fn org_captions(org: &Org, source: &Path) -> HashMap<usize, OrgCaption> {
+    let events: Vec<OrgEvent> = org.iter().collect();
+    let mut captions = HashMap::new();
+    let mut started = 0;
+    for (i, event) in events.iter().enumerate() {
+        let OrgEvent::Start(element) = event else { continue };
+        started += 1;
+        let Element::Keyword(keyword) = element else { continue };
+        if !keyword.key.eq_ignore_ascii_case("caption") {
+            continue;
+        }
+        // The keyword's own `End` follows, then the next sibling's `Start`
+        let image = match events.get(i + 2) {
+            _ if keyword.post_blank > 0 => None,
+            Some(OrgEvent::Start(Element::Table(Table::Org { .. }))) => Some(false),
+            Some(OrgEvent::Start(Element::Paragraph { .. })) if is_org_image_paragraph(&events[i + 3..]) => Some(true),
+            _ => None,
+        };
+        match image {
+            Some(image) => {
+                captions.insert(started, OrgCaption { html: org_inline_html(&keyword.value), image });
+            }
+            None => eprintln!(
+                "[haystack] {}: #+CAPTION: {:?} is not right above an image or table, dropping it",
+                source.display(),
+                keyword.value.trim()
+            ),
+        }
+    }
+    captions
+}
+
+/// Whether the paragraph whose contents start at `events` holds one image link
+/// and nothing else but whitespace.
+fn is_org_image_paragraph(events: &[OrgEvent]) -> bool {
+    let mut images = 0;
+    for event in events {
+        match event {
+            OrgEvent::Start(Element::Link(link)) if org_image_src(link).is_some() => images += 1,
+            OrgEvent::Start(Element::Text { value }) if value.trim().is_empty() => {}
+            OrgEvent::End(Element::Link(_) | Element::Text { .. }) => {}
+            OrgEvent::End(Element::Paragraph { .. }) => return images == 1,
+            _ => return false,
+        }
+    }
+    false
+}
+
+/// The text of org inline markup, for slugs: links become their description
+/// (or target) and statistics cookies are dropped. Emphasis markers are left
+/// for `slugify` to strip.
+fn org_plain_text(text: &str) -> String {
+    static RE_LINK: Lazy<Regex> = Lazy::new(|| Regex::new(r"\[\[([^\]]*)\](?:\[([^\]]*)\])?\]").unwrap());
+    static RE_COOKIE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\[\d*(?:%|/\d*)\]").unwrap());
+    let text = RE_LINK.replace_all(text, |c: &regex::Captures| c.get(2).map_or(&c[1], |desc| desc.as_str()).to_string());
+    RE_COOKIE.replace_all(&text, "").into_owned()
+}
+
+/// Org inline markup (a caption) as HTML, without the paragraph around it.
+fn org_inline_html(text: &str) -> String {
+    let mut bytes = Vec::new();
+    let _ = Org::parse(text.trim()).write_html(&mut bytes);
+    let html = String::from_utf8(bytes).unwrap_or_default();
+    html.strip_prefix("<main><section><p>")
+        .and_then(|h| h.strip_suffix("</p></section></main>"))
+        .map(str::to_string)
+        .unwrap_or_else(|| escape_html(text.trim()))
+}
+
+/// Callout kinds rendered as `div.admonition.<kind>` (org `#+begin_note` etc.)
+const ADMONITION_KINDS: [&str; 5] = ["note", "tip", "important", "warning", "caution"];
+
+fn admonition_kind(name: &str) -> Option<&'static str> {
+    ADMONITION_KINDS.iter().copied().find(|kind| kind.eq_ignore_ascii_case(name))
+}
+
+/// Opening markup of a callout box; `title` defaults to the capitalized kind.
+/// The caller closes the `<div>`.
+fn admonition_start_html(kind: &str, title: Option<&str>) -> String {
+    let title = title.map(str::to_string).unwrap_or_else(|| {
+        let mut chars = kind.chars();
+        chars.next().map(|c| c.to_ascii_uppercase().to_string() + chars.as_str()).unwrap_or_default()
+    });
+    format!(
+        "<div class=\"admonition {}\"><p class=\"admonition-title\">{}</p>",
+        kind,
+        escape_html(&title)
+    )
+}
+
+/// Replaces the `[ ]`, `[X]` and `[-]` markers orgize leaves at the start of
+/// list items with checkboxes: disabled ones, or with `interactive` the same
+/// clickable `input.task` markup as markdown task lists. Returns the new HTML
+/// and the number of checkboxes.
+fn org_checkboxes(html: &str, interactive: bool) -> (String, usize) {
+    static RE_CHECKBOX: Lazy<Regex> = Lazy::new(|| Regex::new(r"<li>(<p>)?\[([ xX-])\] ").unwrap());
+    let mut count = 0;
+    let out = RE_CHECKBOX.replace_all(html, |caps: &regex::Captures| {
+        let para = caps.get(1).map_or("", |m| m.as_str());
+        let partial = &caps[2] == "-";
+        let checked = if matches!(&caps[2], "x" | "X") { " checked=\"\"" } else { "" };
+        let input = if interactive {
+            let class = if partial { "task partial" } else { "task" };
+            format!("<input type=\"checkbox\" class=\"{}\" data-task=\"{}\"{}/>", class, count, checked)
+        } else {
+            let partial = if partial { " class=\"partial\" aria-checked=\"mixed\"" } else { "" };
+            format!("<input disabled=\"\" type=\"checkbox\"{}{}/>", partial, checked)
+        };
+        count += 1;
+        format!("<li>{}{} ", para, input)
+    });
+    (out.into_owned(), count)
 }
 
-fn wrap_html_page(body: String, title: Option<String>, theme: &ThemeConfig) -> String {
+/// Wraps a page body in the site layout: the built-in one, or `template` (the
+/// contents of a `_template.html`) with its `{{ placeholders }}` filled in.
+fn wrap_html_page(
+    body: String,
+    meta: &PageMeta,
+    theme: &ThemeConfig,
+    opts: &RenderOptions,
+    template: Option<&str>,
+) -> String {
     let css = default_css();
-    let (syn_css_light, syn_css_dark) = syntax_css(theme.light.as_deref(), theme.dark.as_deref());
-    let page_title = title.as_deref().unwrap_or("haystack");
+    let (syn_css_light, syn_css_dark) = syntax_css(theme);
+    let page_title = meta.title.as_deref().unwrap_or("haystack");
+    let head_meta = meta
+        .description
+        .as_deref()
+        .map(|d| format!("<meta name=\"description\" content=\"{}\">\n", escape_html(d)))
+        .unwrap_or_default();
+    let head_meta = match &meta.date {
+        Some(date) => format!("{}<meta name=\"date\" content=\"{}\">\n", head_meta, escape_html(date)),
+        None => head_meta,
+    };
+    let og_image = meta.image.as_deref().and_then(|image| absolute_url(image, meta.url_path.as_deref(), opts.base_url.as_deref()));
+    let head_meta = match og_image {
+        Some(url) => format!("{}<meta property=\"og:image\" content=\"{}\">\n", head_meta, escape_html(&url)),
+        None => head_meta,
+    };
+    // Runs in <head> before anything paints, so the stored or default mode
+    // is in place for the first frame
     let theme_bootstrap = r#"(function(){
   try {
     document.documentElement.setAttribute('data-theme', localStorage.getItem('haystack-theme') || 'auto');
+    var codeTheme = localStorage.getItem('haystack-code-theme');
+    if (codeTheme) { document.documentElement.setAttribute('data-code-theme', codeTheme); }
     var ua = navigator.userAgent || '';
     if (/micromessenger/i.test(ua)) {
       document.documentElement.setAttribute('data-hide-share', '1');
     }
   } catch(e) {}
-})();"#;
+})();"#
+        .replace("'auto'", &format!("'{}'", opts.default_user_theme.name()));
     let share_script = r#"(function(){
   function loadHtml2Canvas(){
     return new Promise(function(resolve, reject){
@@ -369,7 +3995,11 @@ fn wrap_html_page(body: String, title: Option<String>, theme: &ThemeConfig) -> S
   }
   var btn = document.getElementById('shareBtn'); if(btn){ btn.addEventListener('click', onShare); }
 })();"#;
-    let controls_html = r#"<div class="theme-controls"><button id="shareBtn" aria-label="Share or save screenshot" title="Share or save screenshot">⇪ Share</button><button id="themeToggle" aria-label="Toggle theme">🌓</button></div>"#;
+    let code_themes = if theme.has_picker() { code_theme_choices(theme) } else { Vec::new() };
+    let controls_html = format!(
+        r#"<div class="theme-controls">{}<button id="shareBtn" aria-label="Share or save screenshot" title="Share or save screenshot">⇪ Share</button><button id="themeToggle" aria-label="Toggle theme">🌓</button></div>"#,
+        code_theme_picker_html(&code_themes)
+    );
     let toggle_script = r#"(function(){
   function setTheme(t){ document.documentElement.setAttribute('data-theme', t); try{ localStorage.setItem('haystack-theme', t); }catch(e){} }
   const btn = document.getElementById('themeToggle');
@@ -380,13 +4010,105 @@ fn wrap_html_page(body: String, title: Option<String>, theme: &ThemeConfig) -> S
   }); }
 })();"#;
     // Prepare syntect CSS for light/dark and auto (media-driven)
-    let syn_light_scoped = scope_syntect_css(&syn_css_light, r#"html[data-theme='light']"#);
-    let syn_dark_scoped = scope_syntect_css(&syn_css_dark, r#"html[data-theme='dark']"#);
-    let syn_auto_light = format!("@media (prefers-color-scheme: light) {{\n{}\n}}", scope_syntect_css(&syn_css_light, r#"html[data-theme='auto']"#));
-    let syn_auto_dark = format!("@media (prefers-color-scheme: dark) {{\n{}\n}}", scope_syntect_css(&syn_css_dark, r#"html[data-theme='auto']"#));
+    let scoped = |css: &str, scope: &str| {
+        let background = opts.code_theme_bg.then(|| code_background_selector(scope));
+        scope_syntect_css(css, scope, background.as_deref())
+    };
+    let syn_light_scoped = scoped(&syn_css_light, r#"html[data-theme='light']"#);
+    let syn_dark_scoped = scoped(&syn_css_dark, r#"html[data-theme='dark']"#);
+    let syn_auto_light = format!("@media (prefers-color-scheme: light) {{\n{}\n}}", scoped(&syn_css_light, r#"html[data-theme='auto']"#));
+    let syn_auto_dark = format!("@media (prefers-color-scheme: dark) {{\n{}\n}}", scoped(&syn_css_dark, r#"html[data-theme='auto']"#));
 
-    let wrap_overrides = "\n/* Force code wrapping */\n.container pre, .container pre code, .container code.hl, .container pre .hl {\n  white-space: pre-wrap;\n  overflow-wrap: anywhere;\n  word-break: break-word;\n}\n/* Controls spacing */\n.theme-controls button + button { margin-left: 8px; }\n/* Hide share button for WeChat in-app browser */\nhtml[data-hide-share='1'] #shareBtn { display: none !important; }\n";
-    let head_extra = read_head_snippet().unwrap_or_default();
+    let code_wrap = if opts.no_wrap_code {
+        "\n/* Code scrolls horizontally; `wrap` blocks opt back in */\n.container pre.wrap, .container pre.wrap code, .container pre.wrap .hl {\n  white-space: pre-wrap;\n  overflow-wrap: anywhere;\n  word-break: break-word;\n}\n"
+    } else {
+        "\n/* Force code wrapping */\n.container pre, .container pre code, .container code.hl, .container pre .hl {\n  white-space: pre-wrap;\n  overflow-wrap: anywhere;\n  word-break: break-word;\n}\n/* `nowrap` blocks scroll instead */\n.container pre.nowrap, .container pre.nowrap code, .container pre.nowrap .hl {\n  white-space: pre;\n  overflow-wrap: normal;\n  word-break: normal;\n}\n"
+    };
+    let wrap_overrides = "/* Controls spacing */\n.theme-controls button + button { margin-left: 8px; }\n/* Hide share button for WeChat in-app browser */\nhtml[data-hide-share='1'] #shareBtn { display: none !important; }\n";
+    let mut feature_css = String::new();
+    let mut body_scripts = String::new();
+    if !code_themes.is_empty() {
+        feature_css.push_str(&code_theme_css(&code_themes, opts.code_theme_bg));
+        body_scripts.push_str(&format!("<script>{}</script>\n", CODE_THEME_SCRIPT));
+    }
+    feature_css.push_str(&language_theme_css(opts, "html"));
+    if meta.has_tasks {
+        body_scripts.push_str(&format!("<script>{}</script>\n", TASKS_SCRIPT));
+    }
+    if meta.has_mermaid {
+        body_scripts.push_str(&format!("<script type=\"module\">{}</script>\n", MERMAID_SCRIPT));
+    }
+    if meta.has_math {
+        body_scripts.push_str(&format!(
+            "<script src=\"{0}/katex.min.js\"></script>\n<script src=\"{0}/contrib/auto-render.min.js\"></script>\n<script>{1}</script>\n",
+            KATEX_CDN, KATEX_SCRIPT
+        ));
+    }
+    if opts.live_reload {
+        body_scripts.push_str(&format!("<script>{}</script>\n", LIVE_RELOAD_SCRIPT.replace("LIVE_RELOAD_PATH", LIVE_RELOAD_PATH)));
+    }
+    if opts.anchor_style != AnchorStyle::None {
+        feature_css.push_str(anchor_css());
+    }
+    if opts.external_link_icon {
+        feature_css.push_str(EXTERNAL_LINK_ICON_CSS);
+    }
+    if meta.sidebar.is_some() {
+        feature_css.push_str(sidebar_css());
+    }
+    for script in &meta.js {
+        body_scripts.push_str(&script.script_html());
+    }
+    // Page stylesheets come after theme/head.html so they can override it
+    let mut head_extra = read_head_snippet().unwrap_or_default();
+    if meta.has_math {
+        head_extra.push_str(&format!("<link rel=\"stylesheet\" href=\"{}/katex.min.css\">\n", KATEX_CDN));
+    }
+    for sheet in &meta.css {
+        head_extra.push_str(&sheet.stylesheet_html());
+    }
+    let body = if opts.embed_frontmatter && !meta.front_matter.is_empty() {
+        format!("{}\n{}", front_matter_script(&meta.front_matter), body)
+    } else {
+        body
+    };
+    let body = if opts.outline && !meta.headings.is_empty() {
+        format!("{}\n{}", outline_script(&meta.headings), body)
+    } else {
+        body
+    };
+    let body = match &meta.nav {
+        Some(nav) => {
+            let link = |link: &Option<NavLink>, rel: &str, label: &str| {
+                link.as_ref()
+                    .map(|l| {
+                        format!(
+                            "<a class=\"{0}\" rel=\"{0}\" href=\"{1}\"><small>{2}</small>{3}</a>",
+                            rel,
+                            escape_html(&l.href),
+                            label,
+                            escape_html(&l.title)
+                        )
+                    })
+                    .unwrap_or_default()
+            };
+            format!(
+                "{}\n<nav class=\"page-nav\">{}{}</nav>",
+                body,
+                link(&nav.prev, "prev", "Previous"),
+                link(&nav.next, "next", "Next")
+            )
+        }
+        None => body,
+    };
+    let body = match meta.source_name.as_deref().filter(|_| opts.include_source) {
+        Some(name) => format!(
+            "{}\n<footer class=\"page-source\"><a href=\"{}\" type=\"text/plain\">View source</a></footer>",
+            body,
+            escape_html(name)
+        ),
+        None => body,
+    };
     let indicator_script = r#"(function(){
   function render(){
     var btn = document.getElementById('themeToggle'); if(!btn) return;
@@ -401,27 +4123,708 @@ fn wrap_html_page(body: String, title: Option<String>, theme: &ThemeConfig) -> S
   var btn = document.getElementById('themeToggle'); if(btn){ btn.addEventListener('click', function(){ setTimeout(render,0); }); }
   var obs = new MutationObserver(render); obs.observe(document.documentElement, { attributes:true, attributeFilter:['data-theme']});
 })();"#;
-    format!(
-        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n<title>{}</title>\n<script>{}</script>\n<style>\n{}\n{}\n{}\n{}\n{}\n{}\n</style>\n{}\n</head>\n<body>\n{}\n<main class=\"container\">\n{}\n</main>\n<script>{}</script>\n<script>{}</script>\n<script>{}</script>\n</body>\n</html>",
-        page_title, theme_bootstrap, css, syn_light_scoped, syn_dark_scoped, syn_auto_light, syn_auto_dark, wrap_overrides, head_extra, controls_html, body, toggle_script, indicator_script, share_script
-    )
+    let head = format!(
+        "<meta charset=\"utf-8\">\n<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n<title>{}</title>\n{}<script>{}</script>\n<style>\n{}\n{}\n{}\n{}\n{}\n{}{}\n{}\n</style>\n{}",
+        escape_html(page_title), head_meta, theme_bootstrap, css, syn_light_scoped, syn_dark_scoped, syn_auto_light, syn_auto_dark, code_wrap, wrap_overrides, feature_css, head_extra
+    );
+    let scripts = format!(
+        "<script>{}</script>\n<script>{}</script>\n<script>{}</script>\n{}",
+        toggle_script, indicator_script, share_script, body_scripts
+    );
+    if let Some(template) = template {
+        static RE_PLACEHOLDER: Lazy<Regex> = Lazy::new(|| Regex::new(r"\{\{\s*([a-z]+)\s*\}\}").unwrap());
+        let html = RE_PLACEHOLDER
+            .replace_all(template, |caps: &regex::Captures| match &caps[1] {
+                "lang" => escape_html(&meta.lang),
+                "title" => escape_html(page_title),
+                "head" => head.clone(),
+                "controls" => controls_html.clone(),
+                "sidebar" => meta.sidebar.clone().unwrap_or_default(),
+                "content" => body.clone(),
+                "scripts" => scripts.clone(),
+                _ => caps[0].to_string(),
+            })
+            .into_owned();
+        return format_html(html, opts);
+    }
+    let sidebar = meta.sidebar.as_deref().map(|s| format!("{}\n", s)).unwrap_or_default();
+    let html = format!(
+        "<!DOCTYPE html>\n<html lang=\"{}\" data-theme=\"{}\">\n<head>\n{}\n</head>\n<body>\n{}\n{}<main class=\"container\">\n{}\n</main>\n{}</body>\n</html>",
+        escape_html(&meta.lang), opts.default_user_theme.name(), head, controls_html, sidebar, body, scripts
+    );
+    format_html(html, opts)
+}
+
+/// Elements `--pretty-html` puts on lines of their own.
+const BLOCK_ELEMENTS: &[&str] = &[
+    "html", "head", "body", "main", "nav", "header", "footer", "section", "article", "aside", "div", "p", "ul", "ol",
+    "li", "dl", "dt", "dd", "table", "thead", "tbody", "tfoot", "tr", "th", "td", "caption", "blockquote", "figure",
+    "figcaption", "details", "summary", "h1", "h2", "h3", "h4", "h5", "h6", "hr", "title", "meta", "link", "base",
+    "form", "fieldset", "select", "optgroup", "option", "noscript", "template",
+];
+
+/// Elements whose content is copied verbatim.
+const RAW_ELEMENTS: &[&str] = &["pre", "textarea", "script", "style"];
+
+const VOID_ELEMENTS: &[&str] = &["area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track", "wbr"];
+
+/// A piece of HTML as seen by `pretty_print_html`.
+enum HtmlToken<'a> {
+    Text(&'a str),
+    /// An element's start or end tag, with its lowercased name
+    Tag { raw: &'a str, name: String, closing: bool },
+    /// A raw element from start to end tag, a comment or a doctype
+    Verbatim { raw: &'a str, block: bool },
+}
+
+fn tokenize_html(html: &str) -> Vec<HtmlToken<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = html;
+    while !rest.is_empty() {
+        let Some(lt) = rest.find('<') else {
+            tokens.push(HtmlToken::Text(rest));
+            break;
+        };
+        if lt > 0 {
+            tokens.push(HtmlToken::Text(&rest[..lt]));
+            rest = &rest[lt..];
+        }
+        let (raw, token) = if rest.starts_with("<!--") {
+            let end = rest.find("-->").map_or(rest.len(), |i| i + 3);
+            (&rest[..end], HtmlToken::Verbatim { raw: &rest[..end], block: true })
+        } else if rest.starts_with("<!") || rest.starts_with("</") || rest[1..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            // Quoted attribute values may contain `>`
+            let mut quote = None;
+            let end = rest
+                .char_indices()
+                .find(|&(_, c)| match quote {
+                    Some(q) if c == q => {
+                        quote = None;
+                        false
+                    }
+                    Some(_) => false,
+                    None if c == '"' || c == '\'' => {
+                        quote = Some(c);
+                        false
+                    }
+                    None => c == '>',
+                })
+                .map_or(rest.len(), |(i, _)| i + 1);
+            let tag = &rest[..end];
+            let closing = tag.starts_with("</");
+            let name: String = tag[if closing { 2 } else { 1 }..]
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric() || *c == '-')
+                .collect::<String>()
+                .to_ascii_lowercase();
+            if tag.starts_with("<!") {
+                (tag, HtmlToken::Verbatim { raw: tag, block: true })
+            } else if !closing && RAW_ELEMENTS.contains(&name.as_str()) {
+                let close = format!("</{}", name);
+                let end = rest[end..]
+                    .to_ascii_lowercase()
+                    .find(&close)
+                    .and_then(|at| rest[end + at..].find('>').map(|gt| end + at + gt + 1))
+                    .unwrap_or(rest.len());
+                (&rest[..end], HtmlToken::Verbatim { raw: &rest[..end], block: name != "textarea" })
+            } else {
+                (tag, HtmlToken::Tag { raw: tag, name, closing })
+            }
+        } else {
+            (&rest[..1], HtmlToken::Text(&rest[..1]))
+        };
+        tokens.push(token);
+        rest = &rest[raw.len()..];
+    }
+    tokens
+}
+
+/// Runs of whitespace that span a line break become one space.
+fn collapse_line_breaks(text: &str) -> String {
+    static RE_BREAK: Lazy<Regex> = Lazy::new(|| Regex::new(r"[ \t]*\r?\n\s*").unwrap());
+    RE_BREAK.replace_all(text, " ").into_owned()
+}
+
+/// Puts block elements on their own lines, indented two spaces per level.
+/// An element holding only text and inline elements stays on one line.
+/// Raw elements (`<pre>`, `<textarea>`, `<script>`, `<style>`) are copied
+/// as they are, so whitespace inside them is never touched.
+fn pretty_print_html(html: &str) -> String {
+    let tokens = tokenize_html(html);
+    let is_block = |name: &str| BLOCK_ELEMENTS.contains(&name);
+    let mut out = String::with_capacity(html.len() + html.len() / 4);
+    let mut line = String::new();
+    let mut depth = 0usize;
+    let emit = |out: &mut String, line: &mut String, depth: usize| {
+        let text = line.trim();
+        if !text.is_empty() {
+            out.push_str(&"  ".repeat(depth));
+            out.push_str(text);
+            out.push('\n');
+        }
+        line.clear();
+    };
+    let mut i = 0;
+    while i < tokens.len() {
+        match &tokens[i] {
+            HtmlToken::Text(text) => line.push_str(&collapse_line_breaks(text)),
+            HtmlToken::Verbatim { raw, block: false } => line.push_str(raw),
+            HtmlToken::Verbatim { raw, block: true } => {
+                emit(&mut out, &mut line, depth);
+                line.push_str(raw);
+                emit(&mut out, &mut line, depth);
+            }
+            HtmlToken::Tag { raw, name, closing: false } if is_block(name) => {
+                emit(&mut out, &mut line, depth);
+                if VOID_ELEMENTS.contains(&name.as_str()) || raw.ends_with("/>") {
+                    line.push_str(raw);
+                    emit(&mut out, &mut line, depth);
+                    i += 1;
+                    continue;
+                }
+                // Keep the element on one line if nothing block-level is inside
+                let mut nested = 0;
+                let mut inline_end = None;
+                for (j, token) in tokens.iter().enumerate().skip(i + 1) {
+                    match token {
+                        HtmlToken::Tag { name: n, closing: true, .. } if n == name && nested == 0 => {
+                            inline_end = Some(j);
+                            break;
+                        }
+                        HtmlToken::Tag { name: n, closing, .. } if n == name => nested += if *closing { -1 } else { 1 },
+                        HtmlToken::Tag { name: n, .. } if is_block(n) => break,
+                        HtmlToken::Verbatim { block: true, .. } => break,
+                        _ => {}
+                    }
+                }
+                line.push_str(raw);
+                match inline_end {
+                    Some(end) => {
+                        for token in &tokens[i + 1..=end] {
+                            match token {
+                                HtmlToken::Text(text) => line.push_str(&collapse_line_breaks(text)),
+                                HtmlToken::Tag { raw, .. } | HtmlToken::Verbatim { raw, .. } => line.push_str(raw),
+                            }
+                        }
+                        emit(&mut out, &mut line, depth);
+                        i = end;
+                    }
+                    None => {
+                        emit(&mut out, &mut line, depth);
+                        depth += 1;
+                    }
+                }
+            }
+            HtmlToken::Tag { raw, name, closing: true } if is_block(name) => {
+                emit(&mut out, &mut line, depth);
+                depth = depth.saturating_sub(1);
+                line.push_str(raw);
+                emit(&mut out, &mut line, depth);
+            }
+            HtmlToken::Tag { raw, .. } => line.push_str(raw),
+        }
+        i += 1;
+    }
+    emit(&mut out, &mut line, depth);
+    out
+}
+
+/// Applies `--pretty-html` and `--line-endings` to a finished page.
+fn format_html(html: String, opts: &RenderOptions) -> String {
+    let html = if opts.pretty_html { pretty_print_html(&html) } else { html };
+    match opts.line_endings {
+        LineEnding::Lf => html,
+        LineEnding::Crlf => html.replace("\r\n", "\n").replace('\n', "\r\n"),
+    }
+}
+
+/// Prev/next links for pages in directories where at least one page sets an
+/// order. Siblings are sorted by order, then file name; unordered pages last.
+fn page_navs(pages: &[PathBuf]) -> HashMap<PathBuf, PageNav> {
+    struct Sibling<'a> {
+        order: Option<i64>,
+        file_name: String,
+        path: &'a PathBuf,
+        title: String,
+    }
+    let mut by_dir: BTreeMap<&Path, Vec<Sibling>> = BTreeMap::new();
+    for path in pages {
+        let Some((order, title)) = page_order_and_title(path) else { continue };
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let title = title.unwrap_or_else(|| path.file_stem().unwrap_or_default().to_string_lossy().into_owned());
+        let dir = path.parent().unwrap_or(Path::new(""));
+        by_dir.entry(dir).or_default().push(Sibling { order, file_name, path, title });
+    }
+    let mut navs = HashMap::new();
+    for (_, mut siblings) in by_dir {
+        if siblings.iter().all(|s| s.order.is_none()) {
+            continue;
+        }
+        siblings.sort_by(|a, b| (a.order.is_none(), a.order, &a.file_name).cmp(&(b.order.is_none(), b.order, &b.file_name)));
+        let link = |i: usize| {
+            let sibling = &siblings[i];
+            let href = output_rel_path(Path::new(&sibling.file_name));
+            NavLink { href: href.to_string_lossy().into_owned(), title: sibling.title.clone() }
+        };
+        for i in 0..siblings.len() {
+            let nav = PageNav {
+                prev: i.checked_sub(1).map(link),
+                next: (i + 1 < siblings.len()).then(|| link(i + 1)),
+            };
+            navs.insert(siblings[i].path.clone(), nav);
+        }
+    }
+    navs
+}
+
+/// Every page of the site for `--sidebar`, grouped by directory.
+#[derive(Debug, Default)]
+struct SiteTree {
+    pages: Vec<SitePage>,
+    dirs: BTreeMap<String, SiteTree>,
+}
+
+#[derive(Debug)]
+struct SitePage {
+    order: Option<i64>,
+    file_name: String,
+    title: String,
+    /// Output path relative to the site root
+    href: PathBuf,
+}
+
+/// Builds the sidebar tree from source files (non-page files are ignored, and
+/// an `.org` page loses to a `.md` page with the same name). Within a
+/// directory the index page comes first, then pages by order and file name
+/// like prev/next navigation.
+fn site_tree(src_dir: &Path, sources: &[PathBuf]) -> SiteTree {
+    let mut tree = SiteTree::default();
+    let mut sorted: Vec<&PathBuf> = sources.iter().collect();
+    sorted.sort_by_key(|p| (p.with_extension(""), p.extension().is_some_and(|e| e != "md")));
+    let mut seen = HashSet::new();
+    for path in sorted {
+        let rel = path.strip_prefix(src_dir).unwrap_or(path);
+        let href = output_rel_path(rel);
+        if href == rel || !seen.insert(href.clone()) {
+            continue;
+        }
+        let Some((order, title)) = page_order_and_title(path) else { continue };
+        let file_name = href.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let title = title.unwrap_or_else(|| rel.file_stem().unwrap_or_default().to_string_lossy().into_owned());
+        let mut node = &mut tree;
+        for dir in rel.parent().into_iter().flat_map(Path::components) {
+            node = node.dirs.entry(dir.as_os_str().to_string_lossy().into_owned()).or_default();
+        }
+        node.pages.push(SitePage { order, file_name, title, href });
+    }
+    fn sort(node: &mut SiteTree) {
+        node.pages.sort_by(|a, b| {
+            let key = |p: &SitePage| (p.file_name != "index.html", p.order.is_none(), p.order, p.file_name.clone());
+            key(a).cmp(&key(b))
+        });
+        node.dirs.values_mut().for_each(sort);
+    }
+    sort(&mut tree);
+    tree
+}
+
+/// `<nav class="sidebar">` for the page at `current` (an output path relative
+/// to the site root). Links are relative to that page; directories are
+/// collapsible and start open along the current page's path.
+fn sidebar_html(tree: &SiteTree, current: &Path) -> String {
+    let depth = current.parent().map_or(0, |p| p.components().count());
+    let up = "../".repeat(depth);
+    fn list(node: &SiteTree, dir: &Path, current: &Path, up: &str, out: &mut String) {
+        out.push_str("<ul>\n");
+        for page in &node.pages {
+            let is_current = page.href == current;
+            out.push_str(&format!(
+                "<li><a href=\"{}{}\"{}>{}</a></li>\n",
+                up,
+                escape_html(&slash_path(&page.href)),
+                if is_current { " class=\"current\" aria-current=\"page\"" } else { "" },
+                escape_html(&page.title)
+            ));
+        }
+        for (name, child) in &node.dirs {
+            let child_dir = dir.join(name);
+            let open = if current.starts_with(&child_dir) { " open" } else { "" };
+            out.push_str(&format!("<li class=\"dir\"><details{}>\n<summary>{}</summary>\n", open, escape_html(name)));
+            list(child, &child_dir, current, up, out);
+            out.push_str("</details></li>\n");
+        }
+        out.push_str("</ul>\n");
+    }
+    let mut out = String::from("<nav class=\"sidebar\" aria-label=\"Site\">\n");
+    list(tree, Path::new(""), current, &up, &mut out);
+    out.push_str("</nav>");
+    out
+}
+
+/// Markdown page marked `draft: true` in its front matter.
+fn is_draft(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "md")
+        && fs::read_to_string(path).is_ok_and(|text| split_front_matter(&normalize_source(text)).0.draft)
+}
+
+/// Order and title of a `.md`/`.org` page, read without converting it.
+fn page_order_and_title(path: &Path) -> Option<(Option<i64>, Option<String>)> {
+    let ext = path.extension().and_then(|s| s.to_str())?;
+    if ext != "md" && ext != "org" {
+        return None;
+    }
+    let text = normalize_source(fs::read_to_string(path).ok()?);
+    if ext == "md" {
+        let (front, body) = split_front_matter(&text);
+        Some((front.order, front.title.or_else(|| extract_title_from_markdown(body))))
+    } else {
+        Some((org_order(&text), extract_title_from_org(&text)))
+    }
+}
+
+/// `.md`/`.org` pages in the same directory as `path` (for `serve`); an
+/// `.org` page is skipped when a `.md` page has the same name.
+fn sibling_pages(path: &Path) -> Vec<PathBuf> {
+    let Some(dir) = path.parent() else { return Vec::new() };
+    let Ok(entries) = fs::read_dir(dir) else { return Vec::new() };
+    let files: Vec<PathBuf> = entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.is_file()).collect();
+    files
+        .iter()
+        .filter(|p| match p.extension().and_then(|s| s.to_str()) {
+            Some("md") => true,
+            Some("org") => !files.contains(&p.with_extension("md")),
+            _ => false,
+        })
+        .cloned()
+        .collect()
+}
+
+/// Name of a layout file that applies to every page in its directory and below.
+const TEMPLATE_FILE: &str = "_template.html";
+
+/// The `_template.html` nearest to `source`, searching its directory and
+/// each parent up to `src_dir`.
+fn template_path(src_dir: &Path, source: &Path) -> Option<PathBuf> {
+    source
+        .parent()?
+        .ancestors()
+        .take_while(|dir| dir.starts_with(src_dir))
+        .map(|dir| dir.join(TEMPLATE_FILE))
+        .find(|path| path.is_file())
+}
+
+/// Name of a settings file whose code themes apply to every page in its
+/// directory and below.
+const DIR_CONFIG_FILE: &str = "_haystack.toml";
+
+/// `_haystack.toml`: code themes for one directory tree of `src/`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DirConfig {
+    theme_light: Option<String>,
+    theme_dark: Option<String>,
+    /// `.tmTheme` files, relative to the `_haystack.toml`
+    theme_light_file: Option<PathBuf>,
+    theme_dark_file: Option<PathBuf>,
+}
+
+/// Every `_haystack.toml` from `source`'s directory up to `src_dir`, nearest first.
+fn dir_config_paths(src_dir: &Path, source: &Path) -> Vec<PathBuf> {
+    let Some(dir) = source.parent() else {
+        return Vec::new();
+    };
+    dir.ancestors()
+        .take_while(|dir| dir.starts_with(src_dir))
+        .map(|dir| dir.join(DIR_CONFIG_FILE))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// The code themes for `source`. For light and dark separately, the nearest
+/// `_haystack.toml` setting `theme_<mode>` or `theme_<mode>_file` decides;
+/// where none does, `theme` (from the command line) applies.
+fn scoped_theme(src_dir: &Path, source: &Path, theme: &ThemeConfig) -> Result<ThemeConfig> {
+    let mut scoped = theme.clone();
+    let (mut light_set, mut dark_set) = (false, false);
+    for path in dir_config_paths(src_dir, source) {
+        let text = fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+        let config: DirConfig = toml::from_str(&text).with_context(|| format!("parsing {}", path.display()))?;
+        let dir = path.parent().unwrap_or(src_dir);
+        if !light_set && (config.theme_light.is_some() || config.theme_light_file.is_some()) {
+            scoped.light = config.theme_light;
+            scoped.light_file = config.theme_light_file.and_then(|file| load_theme_file(&dir.join(file), "light"));
+            light_set = true;
+        }
+        if !dark_set && (config.theme_dark.is_some() || config.theme_dark_file.is_some()) {
+            scoped.dark = config.theme_dark;
+            scoped.dark_file = config.theme_dark_file.and_then(|file| load_theme_file(&dir.join(file), "dark"));
+            dark_set = true;
+        }
+    }
+    Ok(scoped)
+}
+
+/// Contents of the template `template_path` picks for `source`.
+fn find_template(src_dir: &Path, source: &Path) -> Option<String> {
+    let path = template_path(src_dir, source)?;
+    fs::read_to_string(&path)
+        .map_err(|e| eprintln!("[haystack] cannot read {}: {}", path.display(), e))
+        .ok()
 }
 
 fn read_head_snippet() -> Option<String> {
     let path = Path::new("theme").join("head.html");
-    match fs::read_to_string(&path) {
-        Ok(s) => Some(s),
-        Err(_) => None,
+    fs::read_to_string(&path).ok()
+}
+
+/// Local path of an image that should be inlined as SVG markup: `*.svg#inline`
+/// always, any relative `*.svg` when `--inline-svg` is on. Remote, root-relative
+/// and data URLs are left as `<img>`.
+fn inline_svg_target(dest: &str, always: bool) -> Option<&str> {
+    let (path, marked) = match dest.strip_suffix("#inline") {
+        Some(p) => (p, true),
+        None => (dest, false),
+    };
+    let is_local = !path.starts_with('/') && !path.contains("://") && !path.starts_with("data:");
+    let is_svg = path.to_ascii_lowercase().ends_with(".svg");
+    (is_local && is_svg && (marked || always)).then_some(path)
+}
+
+/// Prepares SVG file contents for inlining into HTML: drops the XML prolog and
+/// doctype, `<script>` elements and `on*` event handler attributes, and labels
+/// the root element with the image's alt text.
+fn sanitize_svg(svg: &str, alt: &str) -> String {
+    static RE_PROLOG: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?is)<\?xml.*?\?>|<!DOCTYPE[^>]*>").unwrap());
+    static RE_SCRIPT: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"(?is)<script\b[^>]*/>|<script\b.*?</script\s*>").unwrap());
+    static RE_HANDLER: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r#"(?i)\s+on[a-z]+\s*=\s*("[^"]*"|'[^']*'|[^\s>]+)"#).unwrap());
+    let out = RE_PROLOG.replace_all(svg, "");
+    let out = RE_SCRIPT.replace_all(&out, "");
+    let out = RE_HANDLER.replace_all(&out, "");
+    let out = out.trim();
+    if alt.trim().is_empty() {
+        return out.to_string();
+    }
+    out.replacen("<svg", &format!("<svg role=\"img\" aria-label=\"{}\"", escape_html(alt.trim())), 1)
+}
+
+/// Splits a leading `---` YAML block off a markdown document.
+/// Without a closing delimiter (or when the block isn't a YAML mapping) the
+/// leading `---` is a horizontal rule and the input is returned untouched.
+fn split_front_matter(input: &str) -> (FrontMatter, &str) {
+    let mut lines = input.split_inclusive('\n');
+    match lines.next() {
+        Some(first) if first.trim_end() == "---" => {}
+        _ => return (FrontMatter::default(), input),
+    }
+    let mut offset = input.find('\n').map(|i| i + 1).unwrap_or(input.len());
+    let yaml_start = offset;
+    for line in lines {
+        let yaml_end = offset;
+        offset += line.len();
+        if line.trim_end() != "---" && line.trim_end() != "..." {
+            continue;
+        }
+        let docs = match YamlLoader::load_from_str(&input[yaml_start..yaml_end]) {
+            Ok(docs) => docs,
+            Err(_) => return (FrontMatter::default(), input),
+        };
+        let front = match docs.first() {
+            None => FrontMatter::default(),
+            Some(doc @ Yaml::Hash(_)) => FrontMatter {
+                title: yaml_string(&doc["title"]),
+                description: yaml_string(&doc["description"]),
+                date: yaml_string(&doc["date"]),
+                draft: yaml_string(&doc["draft"]).is_some_and(|d| d == "true"),
+                lang: yaml_string(&doc["lang"]),
+                order: yaml_string(&doc["order"]).or_else(|| yaml_string(&doc["weight"])).and_then(|o| o.parse().ok()),
+                image: yaml_string(&doc["image"]),
+                css: yaml_string_list(&doc["css"]),
+                js: yaml_string_list(&doc["js"]),
+                fields: match yaml_to_json(doc) {
+                    serde_json::Value::Object(map) => map,
+                    _ => serde_json::Map::new(),
+                },
+            },
+            Some(_) => return (FrontMatter::default(), input),
+        };
+        return (front, &input[offset..]);
     }
+    (FrontMatter::default(), input)
+}
+
+fn yaml_string(value: &Yaml) -> Option<String> {
+    let s = match value {
+        Yaml::String(s) => s.clone(),
+        Yaml::Integer(i) => i.to_string(),
+        Yaml::Real(r) => r.clone(),
+        Yaml::Boolean(b) => b.to_string(),
+        _ => return None,
+    };
+    let s = s.trim();
+    if s.is_empty() { None } else { Some(s.to_string()) }
+}
+
+/// A string, or every string of a list.
+fn yaml_string_list(value: &Yaml) -> Vec<String> {
+    match value {
+        Yaml::Array(items) => items.iter().filter_map(yaml_string).collect(),
+        other => yaml_string(other).into_iter().collect(),
+    }
+}
+
+fn yaml_to_json(value: &Yaml) -> serde_json::Value {
+    use serde_json::Value;
+    match value {
+        Yaml::String(s) => Value::String(s.clone()),
+        Yaml::Integer(i) => Value::from(*i),
+        Yaml::Real(r) => r
+            .parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number)
+            .unwrap_or_else(|| Value::String(r.clone())),
+        Yaml::Boolean(b) => Value::Bool(*b),
+        Yaml::Array(items) => Value::Array(items.iter().map(yaml_to_json).collect()),
+        Yaml::Hash(map) => Value::Object(
+            map.iter()
+                .filter_map(|(k, v)| Some((yaml_string(k)?, yaml_to_json(v))))
+                .collect(),
+        ),
+        Yaml::Alias(_) | Yaml::Null | Yaml::BadValue => Value::Null,
+    }
+}
+
+/// Front matter as a JSON data block that client scripts can read.
+fn front_matter_script(fields: &serde_json::Map<String, serde_json::Value>) -> String {
+    json_script("haystack-frontmatter", fields)
+}
+
+/// A heading in the `--outline` tree.
+#[derive(Serialize)]
+struct OutlineNode<'a> {
+    level: u8,
+    id: &'a str,
+    text: &'a str,
+    children: Vec<OutlineNode<'a>>,
+}
+
+/// The page's headings as a JSON tree: each heading holds the deeper ones
+/// after it, up to the next heading at its level or above (as in the table of
+/// contents, skipped levels don't add empty nodes).
+fn outline_script(headings: &[TocEntry]) -> String {
+    fn attach<'a>(nodes: &mut Vec<OutlineNode<'a>>, entry: &'a TocEntry) {
+        match nodes.last_mut() {
+            Some(last) if entry.level > last.level => attach(&mut last.children, entry),
+            _ => nodes.push(OutlineNode { level: entry.level, id: &entry.id, text: &entry.text, children: Vec::new() }),
+        }
+    }
+    let mut roots = Vec::new();
+    for entry in headings {
+        attach(&mut roots, entry);
+    }
+    json_script("haystack-outline", &roots)
+}
+
+/// `value` in a `<script type="application/json">` element with `id`.
+fn json_script(id: &str, value: &impl Serialize) -> String {
+    let json = serde_json::to_string(value).unwrap_or_default();
+    // Keep `</script>` and `<!--` in values from ending the element early
+    let json = json.replace("</", "<\\/").replace("<!--", "<\\u0021--");
+    format!("<script type=\"application/json\" id=\"{}\">{}</script>", id, json)
+}
+
+/// `#+ORDER:` (or `#+WEIGHT:`) of an org document.
+fn org_order(input: &str) -> Option<i64> {
+    org_keyword(input, "order").or_else(|| org_keyword(input, "weight")).and_then(|o| o.parse().ok())
+}
+
+/// Value of the first `#+KEY: value` line in an org document (key is case-insensitive).
+fn org_keyword<'a>(input: &'a str, key: &str) -> Option<&'a str> {
+    input.lines().find_map(|line| {
+        let (k, v) = line.trim().strip_prefix("#+")?.split_once(':')?;
+        let v = v.trim();
+        (k.eq_ignore_ascii_case(key) && !v.is_empty()).then_some(v)
+    })
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+const DEFAULT_EXCERPT_SEPARATOR: &str = "<!--more-->";
+
+/// The page summary: all text before the excerpt separator when the page has
+/// one, else the first `--excerpt-length` characters.
+fn page_excerpt(input: &str, opts: &RenderOptions) -> Option<String> {
+    let before = opts.excerpt_separator.as_deref().and_then(|separator| {
+        // Found as raw HTML only, so a separator in code or text doesn't count
+        MdParser::new_ext(input, markdown_options()).into_offset_iter().find_map(|(ev, range)| match ev {
+            Event::Html(h) | Event::InlineHtml(h) if h.trim() == separator => Some(&input[..range.start]),
+            _ => None,
+        })
+    });
+    match before {
+        Some(before) => markdown_excerpt(before, usize::MAX),
+        None => markdown_excerpt(input, opts.excerpt_length),
+    }
+}
+
+/// Plain-text summary of a markdown document: paragraph and list text with
+/// formatting removed, skipping headings, code blocks and raw HTML. Cut on a
+/// word boundary at `max_chars` (an ellipsis marks truncation).
+fn markdown_excerpt(input: &str, max_chars: usize) -> Option<String> {
+    let mut text = String::new();
+    let mut skip_depth = 0usize;
+    for ev in MdParser::new_ext(input, markdown_options()) {
+        match ev {
+            Event::Start(Tag::Heading { .. } | Tag::CodeBlock(_) | Tag::HtmlBlock | Tag::Image { .. }) => {
+                skip_depth += 1
+            }
+            Event::End(TagEnd::Heading(_) | TagEnd::CodeBlock | TagEnd::HtmlBlock | TagEnd::Image) => {
+                skip_depth = skip_depth.saturating_sub(1)
+            }
+            Event::Text(t) | Event::Code(t) if skip_depth == 0 => text.push_str(&t),
+            Event::SoftBreak
+            | Event::HardBreak
+            | Event::End(TagEnd::Paragraph | TagEnd::Item | TagEnd::TableCell | TagEnd::BlockQuote(_))
+                if skip_depth == 0 =>
+            {
+                text.push(' ')
+            }
+            _ => {}
+        }
+    }
+    let words: Vec<&str> = text.split_whitespace().collect();
+    if words.is_empty() || max_chars == 0 {
+        return None;
+    }
+    let mut excerpt = String::new();
+    for word in words.iter() {
+        let extra = if excerpt.is_empty() { 0 } else { 1 };
+        if excerpt.chars().count() + extra + word.chars().count() > max_chars {
+            if excerpt.is_empty() {
+                excerpt = word.chars().take(max_chars).collect();
+            }
+            excerpt = excerpt.trim_end_matches(|c: char| c.is_ascii_punctuation()).to_string();
+            excerpt.push('…');
+            return Some(excerpt);
+        }
+        if extra == 1 {
+            excerpt.push(' ');
+        }
+        excerpt.push_str(word);
+    }
+    Some(excerpt)
 }
 
 fn extract_title_from_markdown(input: &str) -> Option<String> {
-    let mut options = Options::empty();
-    options.insert(Options::ENABLE_TABLES);
-    options.insert(Options::ENABLE_FOOTNOTES);
-    options.insert(Options::ENABLE_STRIKETHROUGH);
-    options.insert(Options::ENABLE_TASKLISTS);
-    let parser = MdParser::new_ext(input, options);
+    let parser = MdParser::new_ext(input, markdown_options());
     let mut in_heading = false;
     let mut title = String::new();
     for ev in parser {
@@ -448,7 +4851,12 @@ fn extract_title_from_markdown(input: &str) -> Option<String> {
     None
 }
 
+/// Page title for an org document, by precedence: `#+HTML_TITLE:` anywhere in
+/// the file, then whichever of `#+TITLE:` or the first headline comes first.
 fn extract_title_from_org(input: &str) -> Option<String> {
+    if let Some(title) = org_keyword(input, "html_title") {
+        return Some(title.to_string());
+    }
     for line in input.lines() {
         let l = line.trim();
         if l.is_empty() { continue; }
@@ -544,8 +4952,14 @@ p { margin: 1em 0; }
 a { color: var(--link); text-decoration: underline; text-decoration-thickness: 1px; text-underline-offset: 2px; text-decoration-skip-ink: auto; }
 a:hover { opacity: 0.9; }
 ::selection { background: color-mix(in srgb, var(--link) 25%, transparent); }
+figure { margin: 1.6rem 0; text-align: center; }
+.embed { margin: 1.6rem 0; }
+.embed iframe { display: block; width: 100%; aspect-ratio: 16 / 9; height: auto; border: 0; border-radius: 2px; }
+.embed-codepen iframe { aspect-ratio: auto; height: 26rem; }
+figcaption { margin-top: 0.5rem; color: var(--muted); font-size: 0.9em; font-style: italic; }
 img, video { max-width: 100%; height: auto; border-radius: 2px; box-shadow: 0 1px 0 var(--shadow); }
 hr { border: 0; border-top: 1px dashed var(--border); margin: 2.2rem 0; }
+section.page + section.page { border-top: 1px dashed var(--border); margin-top: 3rem; }
 ul, ol { padding-left: 1.2rem; }
 li { margin: 0.35rem 0; }
 blockquote {
@@ -562,54 +4976,362 @@ pre {
   padding: 0.9rem; border-radius: 6px; overflow: auto; border: 1px solid var(--border);
 }
 code { background: var(--code-bg); padding: 0.1rem 0.35rem; border-radius: 4px; }
+pre .line { display: inline-block; min-width: 100%; }
+pre .diff-add { background: color-mix(in srgb, #2ea043 20%, transparent); }
+pre .diff-del { background: color-mix(in srgb, #f85149 20%, transparent); }
+pre .diff-hunk { background: color-mix(in srgb, var(--link) 10%, transparent); }
+pre .diff-hunk, pre .diff-hunk span { color: var(--muted); }
+/* ```ansi blocks: a palette readable on light and dark backgrounds */
+.ansi-bold { font-weight: bold; } .ansi-dim { opacity: 0.7; } .ansi-italic { font-style: italic; } .ansi-underline { text-decoration: underline; }
+.ansi-black { color: #555; } .ansi-red { color: #cd3131; } .ansi-green { color: #0dbc79; } .ansi-yellow { color: #c4a000; }
+.ansi-blue { color: #2472c8; } .ansi-magenta { color: #bc3fbc; } .ansi-cyan { color: #11a8cd; } .ansi-white { color: #999; }
+.ansi-bright-black { color: #767676; } .ansi-bright-red { color: #f14c4c; } .ansi-bright-green { color: #23d18b; } .ansi-bright-yellow { color: #e5c100; }
+.ansi-bright-blue { color: #3b8eea; } .ansi-bright-magenta { color: #d670d6; } .ansi-bright-cyan { color: #29b8db; } .ansi-bright-white { color: #bbb; }
+.ansi-bg-black { background: #555; } .ansi-bg-red { background: #cd3131; } .ansi-bg-green { background: #0dbc79; } .ansi-bg-yellow { background: #c4a000; }
+.ansi-bg-blue { background: #2472c8; } .ansi-bg-magenta { background: #bc3fbc; } .ansi-bg-cyan { background: #11a8cd; } .ansi-bg-white { background: #999; }
+.ansi-bg-bright-black { background: #767676; } .ansi-bg-bright-red { background: #f14c4c; } .ansi-bg-bright-green { background: #23d18b; } .ansi-bg-bright-yellow { background: #e5c100; }
+.ansi-bg-bright-blue { background: #3b8eea; } .ansi-bg-bright-magenta { background: #d670d6; } .ansi-bg-bright-cyan { background: #29b8db; } .ansi-bg-bright-white { background: #bbb; }
 pre code { padding: 0; background: transparent; }
+pre .line[id] { scroll-margin-top: 2rem; }
+pre .line:target { background: color-mix(in srgb, var(--link) 18%, transparent); }
+pre .highlighted-line { background: color-mix(in srgb, var(--link) 12%, transparent); box-shadow: inset 3px 0 0 color-mix(in srgb, var(--link) 60%, transparent); }
+/* --line-numbers: a hanging indent keeps wrapped lines clear of the gutter */
+pre.line-numbers code { counter-reset: line; }
+pre.line-numbers .line { box-sizing: border-box; padding-left: calc(var(--line-digits) * 1ch + 1.5ch); text-indent: calc(-1 * (var(--line-digits) * 1ch + 1.5ch)); }
+pre.line-numbers .line::before {
+  counter-increment: line; content: counter(line);
+  display: inline-block; width: calc(var(--line-digits) * 1ch); margin-right: 1.5ch; text-indent: 0; text-align: right;
+  color: var(--muted); user-select: none; -webkit-user-select: none;
+}
 table { width: 100%; border-collapse: collapse; margin: 1.2rem 0; }
+.table-wrap { overflow-x: auto; margin: 1.2rem 0; }
+.table-wrap > table { margin: 0; }
 th, td { padding: 0.5rem 0.6rem; border: 1px solid var(--border); text-align: left; }
 thead th { background: color-mix(in srgb, var(--code-bg) 85%, transparent); }
 details { border: 1px solid var(--border); border-radius: 6px; padding: 0.6rem 0.9rem; background: color-mix(in srgb, var(--code-bg) 75%, transparent); }
 summary { cursor: pointer; font-weight: 600; }
+.progress-cookie { display: inline-block; font-size: 0.7em; font-weight: 600; vertical-align: middle; padding: 0.05rem 0.45rem; border-radius: 999px; border: 1px solid var(--border); color: var(--muted); background: var(--code-bg); }
+.progress-cookie.complete { color: #2ea043; border-color: color-mix(in srgb, #2ea043 50%, transparent); }
+input[type=checkbox].partial { opacity: 0.55; }
+.admonition { margin: 1.2rem 0; padding: 0.7rem 1rem; border-left: 4px solid var(--admonition, var(--link)); border-radius: 4px; background: color-mix(in srgb, var(--admonition, var(--link)) 8%, transparent); }
+.admonition > :last-child { margin-bottom: 0; }
+.admonition-title { margin: 0 0 0.4rem; font-weight: 600; color: var(--admonition, var(--link)); }
+.admonition.tip { --admonition: #2ea043; }
+.admonition.important { --admonition: #8957e5; }
+.admonition.warning { --admonition: #d29922; }
+.admonition.caution { --admonition: #f85149; }
+.toc { margin: 1.2rem 0 2rem; padding: 0.6rem 1rem; border: 1px solid var(--border); border-radius: 6px; font-size: 0.92em; }
+.toc ul { margin: 0.2rem 0; padding-left: 1.1rem; }
+.toc > ul { padding-left: 0.2rem; list-style: none; }
+.toc a { text-decoration: none; }
+.page-nav { display: flex; justify-content: space-between; gap: 1rem; margin-top: 3rem; }
+.page-nav a { display: flex; flex-direction: column; max-width: 48%; padding: 0.6rem 0.9rem; border: 1px solid var(--border); border-radius: 6px; text-decoration: none; }
+.page-nav a small { color: var(--muted); }
+.page-nav a.next { margin-left: auto; text-align: right; }
+.page-source { margin-top: 2.5rem; padding-top: 0.8rem; border-top: 1px solid var(--border); font-size: 0.85em; }
+.page-source a { color: var(--muted); }
+.code-block { margin: 1rem 0; }
+.code-title { display: inline-block; font-family: ui-monospace, SFMono-Regular, Menlo, Consolas, monospace; font-size: 0.8em; color: var(--muted); background: var(--code-bg); border: 1px solid var(--border); border-bottom: 0; border-radius: 6px 6px 0 0; padding: 0.25rem 0.8rem; }
+.code-block > pre, .code-block > .code-collapse > pre { margin-top: 0; border-top-left-radius: 0; }
+.code-collapse { margin: 1rem 0; }
+.code-collapse > pre { margin-bottom: 0; border-bottom-left-radius: 0; border-bottom-right-radius: 0; max-height: 9em; overflow: hidden; -webkit-mask-image: linear-gradient(to bottom, #000 55%, transparent); mask-image: linear-gradient(to bottom, #000 55%, transparent); }
+.code-collapse:has(> details[open]) > pre { display: none; }
+.code-collapse > details { padding: 0; border: 0; background: transparent; }
+.code-collapse > details > summary { font-weight: normal; color: var(--muted); padding: 0.3rem 0; }
 kbd { font-family: inherit; background: var(--code-bg); border: 1px solid var(--border); border-bottom-width: 2px; padding: 0 0.35rem; border-radius: 4px; }
+/* Readers who ask for reduced motion get every transition and animation finished instantly */
+@media (prefers-reduced-motion: reduce) {
+  *, *::before, *::after { animation-duration: 0.01ms !important; animation-iteration-count: 1 !important; transition-duration: 0.01ms !important; scroll-behavior: auto !important; }
+}
 @media (max-width: 600px) { body { font-size: 19px; } .container { padding: 0 22px 56px; } }
 @media (min-width: 900px) { body { font-size: 18px; } .container { padding: 36px 22px 56px; } }
 "#
 }
 
-static SYNTAX_SET: Lazy<SyntaxSet> = Lazy::new(|| SyntaxSet::load_defaults_newlines());
-static THEME_SET: Lazy<ThemeSet> = Lazy::new(ThemeSet::load_defaults);
+/// The sidebar sits left of the content on wide screens and above it otherwise.
+fn sidebar_css() -> &'static str {
+    r#".sidebar { font-size: 0.9em; padding: 28px 18px 0; }
+.sidebar ul { list-style: none; margin: 0; padding-left: 0; }
+.sidebar ul ul { padding-left: 0.9rem; border-left: 1px solid var(--border); margin-left: 0.3rem; }
+.sidebar li { margin: 0.15rem 0; }
+.sidebar a { text-decoration: none; color: var(--fg); }
+.sidebar a:hover { color: var(--link); }
+.sidebar a.current { color: var(--link); font-weight: 600; }
+.sidebar details { border: 0; padding: 0; background: transparent; }
+.sidebar summary { font-weight: 600; color: var(--muted); padding: 0.15rem 0; }
+@media (min-width: 1100px) {
+  .sidebar { position: fixed; top: 0; left: 0; bottom: 0; width: 16rem; overflow-y: auto; border-right: 1px solid var(--border); }
+  .sidebar + .container { margin-left: max(17rem, calc((100% - 70ch) / 2)); }
+}
+"#
+}
+
+const EXTERNAL_LINK_ICON_CSS: &str = "a.external::after { content: \"\\2197\"; margin-left: 0.15em; font-size: 0.85em; text-decoration: none; display: inline-block; }\n";
+
+fn anchor_css() -> &'static str {
+    r#".anchor { color: var(--muted); text-decoration: none; }
+.anchor:hover { color: var(--link); }
+.anchor-hash { margin-right: 0.3em; }
+.anchor-icon { margin-left: 0.35em; opacity: 0; }
+h1:hover .anchor-icon, h2:hover .anchor-icon, h3:hover .anchor-icon,
+h4:hover .anchor-icon, h5:hover .anchor-icon, h6:hover .anchor-icon, .anchor-icon:focus { opacity: 1; }
+"#
+}
+
+/// syntect's defaults plus the grammars in syntaxes/ (TOML, TypeScript,
+/// JSX/TSX, Svelte, Zig), linked into one dump by build.rs, plus any
+/// `--syntax-dir` grammars.
+static SYNTAX_SET: Lazy<SyntaxSet> = Lazy::new(|| {
+    let bundled: SyntaxSet = syntect::dumps::from_binary(include_bytes!(concat!(env!("OUT_DIR"), "/syntaxes.packdump")));
+    match SYNTAX_DIR.get() {
+        Some(dir) => with_user_syntaxes(bundled, dir),
+        None => bundled,
+    }
+});
+
+/// `--syntax-dir`, set once while reading the command line.
+static SYNTAX_DIR: OnceCell<PathBuf> = OnceCell::new();
+
+/// `set` plus every `.sublime-syntax` file under `dir`. Files that don't parse
+/// are reported and skipped. Relinking the whole set takes a moment, so this
+/// only runs when a directory is given.
+fn with_user_syntaxes(set: SyntaxSet, dir: &Path) -> SyntaxSet {
+    let mut builder = set.into_builder();
+    let mut files: Vec<PathBuf> = WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .map(|e| e.into_path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "sublime-syntax"))
+        .collect();
+    files.sort();
+    for path in files {
+        let name = path.file_stem().and_then(|s| s.to_str()).map(str::to_string);
+        let loaded = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| SyntaxDefinition::load_from_str(&text, true, name.as_deref()).map_err(|e| e.to_string()));
+        match loaded {
+            Ok(syntax) => builder.add(syntax),
+            Err(e) => eprintln!("[haystack] cannot load syntax {}: {}; skipping it", path.display(), e),
+        }
+    }
+    builder.build()
+}
+static THEME_SET: Lazy<ThemeSet> = Lazy::new(ThemeSet::load_defaults);
+
+/// A `.tmTheme` file as a theme. A file that can't be read or parsed is
+/// reported and `None`, so the named `mode` theme is used instead.
+fn load_theme_file(path: &Path, mode: &str) -> Option<Theme> {
+    match ThemeSet::get_theme(path) {
+        Ok(theme) => Some(theme),
+        Err(e) => {
+            eprintln!("[haystack] cannot load {} theme {}: {}; using the named theme", mode, path.display(), e);
+            None
+        }
+    }
+}
+
+/// syntect CSS for the page's default light and dark code themes: the
+/// `--theme-*-file` theme if loaded, else the first named one, else a fallback.
+fn syntax_css(theme: &ThemeConfig) -> (String, String) {
+    let light_name = theme.light_names().first().copied();
+    let dark_name = theme.dark_names().first().copied();
+    let light_theme = theme.light_file.as_ref().or_else(|| resolve_theme(light_name)).unwrap_or_else(|| {
+        if light_name.is_some() {
+            eprintln!("[haystack] theme-light not found, using InspiredGitHub/base16-ocean.light fallback");
+        }
+        THEME_SET
+            .themes
+            .get("InspiredGitHub")
+            .or_else(|| THEME_SET.themes.get("base16-ocean.light"))
+            .expect("InspiredGitHub or base16-ocean.light theme present")
+    });
+
+    let dark_theme = theme.dark_file.as_ref().or_else(|| resolve_theme(dark_name)).unwrap_or_else(|| {
+        if dark_name.is_some() {
+            eprintln!("[haystack] theme-dark not found, using base16-ocean.dark/Solarized (dark) fallback");
+        }
+        THEME_SET
+            .themes
+            .get("base16-ocean.dark")
+            .or_else(|| THEME_SET.themes.get("Solarized (dark)"))
+            .expect("base16-ocean.dark or Solarized (dark) theme present")
+    });
+    let light = css_for_theme_with_class_style(light_theme, ClassStyle::Spaced).unwrap_or_default();
+    let dark = css_for_theme_with_class_style(dark_theme, ClassStyle::Spaced).unwrap_or_default();
+    (light, dark)
+}
+
+/// A code theme readers can pick: its picker label, `data-code-theme` id and theme.
+struct CodeThemeChoice {
+    name: String,
+    id: String,
+    dark: bool,
+    theme: &'static Theme,
+}
+
+/// Every resolvable theme from the light and dark lists, in order, without duplicates.
+fn code_theme_choices(theme: &ThemeConfig) -> Vec<CodeThemeChoice> {
+    let mut seen = HashSet::new();
+    let listed = theme
+        .light_names()
+        .into_iter()
+        .map(|n| (n, false))
+        .chain(theme.dark_names().into_iter().map(|n| (n, true)));
+    let mut choices = Vec::new();
+    for (name, dark) in listed {
+        let Some(resolved) = resolve_theme(Some(name)) else {
+            eprintln!("[haystack] code theme '{}' not found, leaving it out of the picker", name);
+            continue;
+        };
+        let id = slugify(name);
+        if seen.insert(id.clone()) {
+            choices.push(CodeThemeChoice { name: name.to_string(), id, dark, theme: resolved });
+        }
+    }
+    choices
+}
+
+/// Highlighted code blocks under `scope`, for `--code-theme-bg`.
+fn code_background_selector(scope: &str) -> String {
+    format!("{0} pre:has(> code.hl), {0} .code-collapse > pre", scope)
+}
+
+/// Each choice's syntect CSS scoped by `html[data-code-theme='id']`. Emitted
+/// after the light/dark rules so a picked theme wins in every color mode.
+fn code_theme_css(choices: &[CodeThemeChoice], theme_bg: bool) -> String {
+    let mut css = String::from(
+        ".theme-controls select { border: 1px solid var(--fg); background: var(--bg); color: var(--fg); border-radius: 999px; padding: 3px 8px; font: inherit; font-size: 0.9rem; margin-right: 8px; }\n",
+    );
+    for choice in choices {
+        let theme_css = css_for_theme_with_class_style(choice.theme, ClassStyle::Spaced).unwrap_or_default();
+        let scope = format!("html[data-code-theme='{}']", choice.id);
+        let background = theme_bg.then(|| code_background_selector(&scope));
+        css.push_str(&scope_syntect_css(&theme_css, &scope, background.as_deref()));
+    }
+    css
+}
+
+fn language_theme_class(name: &str) -> String {
+    format!("code-theme-{}", slugify(name))
+}
+
+/// Syntect CSS for each `[code_themes]` theme, scoped to the blocks using it.
+/// The extra `pre` makes these rules outrank the page and picker themes.
+fn language_theme_css(opts: &RenderOptions, scope: &str) -> String {
+    let mut seen = HashSet::new();
+    let mut css = String::new();
+    for name in opts.code_themes.values() {
+        let Some(theme) = resolve_theme(Some(name)).filter(|_| seen.insert(name)) else {
+            continue;
+        };
+        let theme_css = css_for_theme_with_class_style(theme, ClassStyle::Spaced).unwrap_or_default();
+        let block = format!("{} pre.{}", scope, language_theme_class(name));
+        let background = opts.code_theme_bg.then_some(block.as_str());
+        css.push_str(&scope_syntect_css(&theme_css, &block, background));
+    }
+    css
+}
+
+fn code_theme_picker_html(choices: &[CodeThemeChoice]) -> String {
+    if choices.is_empty() {
+        return String::new();
+    }
+    let group = |dark: bool| -> String {
+        choices
+            .iter()
+            .filter(|c| c.dark == dark)
+            .map(|c| format!("<option value=\"{}\">{}</option>", c.id, escape_html(&c.name)))
+            .collect()
+    };
+    format!(
+        r#"<select id="codeThemePicker" aria-label="Code theme" title="Code theme"><option value="">Code: default</option><optgroup label="Light">{}</optgroup><optgroup label="Dark">{}</optgroup></select>"#,
+        group(false),
+        group(true)
+    )
+}
+
+/// Restores and saves `--interactive-tasks` checkbox state per page in localStorage.
+const TASKS_SCRIPT: &str = r#"(function(){
+  var key = 'haystack-tasks:' + location.pathname;
+  var saved = {};
+  try { saved = JSON.parse(localStorage.getItem(key) || '{}') || {}; } catch(e) {}
+  document.querySelectorAll('input.task[data-task]').forEach(function(box){
+    var i = box.getAttribute('data-task');
+    if (Object.prototype.hasOwnProperty.call(saved, i)) { box.checked = !!saved[i]; }
+    box.addEventListener('change', function(){
+      saved[i] = box.checked;
+      try { localStorage.setItem(key, JSON.stringify(saved)); } catch(e) {}
+    });
+  });
+})();"#;
+
+// Draws `--mermaid` diagrams in the page's current color mode
+const MERMAID_SCRIPT: &str = r#"import mermaid from 'https://cdn.jsdelivr.net/npm/mermaid@11/dist/mermaid.esm.min.mjs';
+var mode = document.documentElement.getAttribute('data-theme');
+var dark = mode === 'dark' || (mode !== 'light' && window.matchMedia('(prefers-color-scheme: dark)').matches);
+mermaid.initialize({ startOnLoad: true, theme: dark ? 'dark' : 'default' });"#;
+
+/// KaTeX release the `--math` assets load from.
+const KATEX_CDN: &str = "https://cdn.jsdelivr.net/npm/katex@0.16.11/dist";
+
+// Typesets only haystack's math elements, so TeX-like text elsewhere is left alone
+const KATEX_SCRIPT: &str = r#"document.querySelectorAll('.math.inline, .math.display').forEach(function(el){
+  renderMathInElement(el, { delimiters: [
+    { left: '\\[', right: '\\]', display: true },
+    { left: '\\(', right: '\\)', display: false }
+  ], throwOnError: false });
+});"#;
 
-fn syntax_css(light_name: Option<&str>, dark_name: Option<&str>) -> (String, String) {
-    let light_theme = resolve_theme(light_name).unwrap_or_else(|| {
-        if !light_name.is_none() {
-            eprintln!("[haystack] theme-light not found, using InspiredGitHub/base16-ocean.light fallback");
-        }
-        THEME_SET
-            .themes
-            .get("InspiredGitHub")
-            .or_else(|| THEME_SET.themes.get("base16-ocean.light"))
-            .expect("InspiredGitHub or base16-ocean.light theme present")
-    });
+// Polls until the server reports a new generation, then reloads; after an
+// error (server restarting) it retries every second
+const LIVE_RELOAD_SCRIPT: &str = r#"(function(){
+  var seen = null;
+  function poll(){
+    fetch('/LIVE_RELOAD_PATH' + (seen === null ? '' : '?since=' + encodeURIComponent(seen)), { cache: 'no-store' })
+      .then(function(r){ if (!r.ok) { throw new Error(r.status); } return r.text(); })
+      .then(function(generation){
+        if (seen !== null && generation !== seen) { location.reload(); return; }
+        seen = generation;
+        poll();
+      })
+      .catch(function(){ setTimeout(poll, 1000); });
+  }
+  poll();
+})();"#;
 
-    let dark_theme = resolve_theme(dark_name).unwrap_or_else(|| {
-        if !dark_name.is_none() {
-            eprintln!("[haystack] theme-dark not found, using base16-ocean.dark/Solarized (dark) fallback");
-        }
-        THEME_SET
-            .themes
-            .get("base16-ocean.dark")
-            .or_else(|| THEME_SET.themes.get("Solarized (dark)"))
-            .expect("base16-ocean.dark or Solarized (dark) theme present")
-    });
-    let light = css_for_theme_with_class_style(light_theme, ClassStyle::Spaced).unwrap_or_default();
-    let dark = css_for_theme_with_class_style(dark_theme, ClassStyle::Spaced).unwrap_or_default();
-    (light, dark)
-}
+const CODE_THEME_SCRIPT: &str = r#"(function(){
+  var sel = document.getElementById('codeThemePicker'); if(!sel) return;
+  var root = document.documentElement;
+  sel.value = root.getAttribute('data-code-theme') || '';
+  if(sel.selectedIndex < 0){ sel.value = ''; root.removeAttribute('data-code-theme'); }
+  sel.addEventListener('change', function(){
+    var v = sel.value;
+    if(v){ root.setAttribute('data-code-theme', v); } else { root.removeAttribute('data-code-theme'); }
+    try{ if(v){ localStorage.setItem('haystack-code-theme', v); } else { localStorage.removeItem('haystack-code-theme'); } }catch(e){}
+  });
+})();"#;
 
-fn scope_syntect_css(css: &str, scope: &str) -> String {
+/// The theme's own `.code` rule (its background and default text color) is
+/// dropped, or moved onto `background` when given.
+///
+/// The scoped rules are sorted by selector (the background rule first), so
+/// the CSS is byte-for-byte the same for the same theme whatever order the
+/// theme file lists its scopes in.
+fn scope_syntect_css(css: &str, scope: &str, background: Option<&str>) -> String {
+    static RE_COMMENT: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?s)/\*.*?\*/").unwrap());
     // Naively prefix each CSS rule's selectors with the scope.
     // This avoids selector collisions between light/dark theme rules.
     let mut out = String::new();
+    let mut rules: Vec<(String, &str)> = Vec::new();
     for chunk in css.split('}') {
         if let Some((selectors, body)) = chunk.split_once('{') {
+            let selectors = RE_COMMENT.replace_all(selectors, "");
+            if selectors.trim() == ".code" {
+                if let Some(background) = background {
+                    out.push_str(&format!("{} {{\n{}}}\n", background, body));
+                }
+                continue;
+            }
             let scoped_selectors = selectors
                 .split(',')
                 .map(|s| s.trim())
@@ -617,12 +5339,17 @@ fn scope_syntect_css(css: &str, scope: &str) -> String {
                 .map(|s| format!("{} {}", scope, s))
                 .collect::<Vec<_>>()
                 .join(", ");
-            out.push_str(&scoped_selectors);
-            out.push_str("{\n");
-            out.push_str(body);
-            out.push_str("}\n");
+            rules.push((scoped_selectors, body));
         }
     }
+    // Stable, so rules with the same selector keep their relative order
+    rules.sort_by(|a, b| a.0.cmp(&b.0));
+    for (selectors, body) in rules {
+        out.push_str(&selectors);
+        out.push_str("{\n");
+        out.push_str(body);
+        out.push_str("}\n");
+    }
     out
 }
 
@@ -672,31 +5399,628 @@ fn normalize_name(s: &str) -> String {
         .collect()
 }
 
+#[derive(Debug, Serialize)]
+struct ThemeInfo {
+    name: String,
+    kind: ThemeKind,
+    background: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ThemeKind {
+    Light,
+    Dark,
+}
+
+/// Theme names with their background color, classified as light or dark by
+/// the background's relative luminance (themes without one count as light).
+fn theme_infos() -> Vec<ThemeInfo> {
+    let mut infos: Vec<ThemeInfo> = THEME_SET
+        .themes
+        .iter()
+        .map(|(name, theme)| {
+            let bg = theme.settings.background;
+            let luminance = bg.map(|c| {
+                (0.2126 * f64::from(c.r) + 0.7152 * f64::from(c.g) + 0.0722 * f64::from(c.b)) / 255.0
+            });
+            ThemeInfo {
+                name: name.clone(),
+                kind: if luminance.is_some_and(|l| l < 0.5) { ThemeKind::Dark } else { ThemeKind::Light },
+                background: bg.map(|c| format!("#{:02x}{:02x}{:02x}", c.r, c.g, c.b)),
+            }
+        })
+        .collect();
+    infos.sort_unstable_by_key(|t| t.name.to_ascii_lowercase());
+    infos
+}
+
+/// What `haystack info` reports.
+#[derive(Debug, Serialize)]
+struct BuildInfo {
+    version: &'static str,
+    /// `os-arch` the binary was built for
+    target: String,
+    /// `debug` or `release`
+    profile: &'static str,
+    syntaxes: usize,
+    /// Names of the syntaxes haystack adds to syntect's defaults
+    extra_syntaxes: Vec<&'static str>,
+    themes: usize,
+}
+
+fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        target: format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH),
+        profile: if cfg!(debug_assertions) { "debug" } else { "release" },
+        syntaxes: SYNTAX_SET.syntaxes().len(),
+        extra_syntaxes: extra_syntax_names(),
+        themes: THEME_SET.themes.len(),
+    }
+}
+
+/// Syntaxes in `SYNTAX_SET` that syntect doesn't ship.
+fn extra_syntax_names() -> Vec<&'static str> {
+    let defaults = SyntaxSet::load_defaults_newlines();
+    SYNTAX_SET
+        .syntaxes()
+        .iter()
+        .filter(|s| defaults.find_syntax_by_name(&s.name).is_none())
+        .map(|s| s.name.as_str())
+        .collect()
+}
+
+fn print_build_info(info: &BuildInfo) {
+    println!("haystack {}", info.version);
+    println!("target:   {} ({})", info.target, info.profile);
+    println!("syntaxes: {} (bundled extras: {})", info.syntaxes, info.extra_syntaxes.join(", "));
+    println!("themes:   {}", info.themes);
+}
+
 fn list_themes() {
     let mut names: Vec<&str> = THEME_SET.themes.keys().map(|s| s.as_str()).collect();
-    names.sort_unstable_by(|a, b| a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase()));
+    names.sort_unstable_by_key(|a| a.to_ascii_lowercase());
     println!("Available themes ({}):", names.len());
     for n in names {
         println!("- {}", n);
     }
 }
 
-fn highlight_code(code: &str, lang: Option<&str>) -> String {
+/// Settings from a fenced code block's info string, e.g. ```` ```rust nowrap ````.
+#[derive(Debug, Clone, Default)]
+struct CodeInfo {
+    lang: Option<String>,
+    /// Per-block override of `--no-wrap-code` (`wrap` / `nowrap` tokens)
+    wrap: Option<bool>,
+    /// File name shown in a caption bar (`title="..."` / `filename="..."`)
+    title: Option<String>,
+    /// `file:PATH` in place of the language: show that file (relative to the
+    /// page), highlighted according to its extension
+    file: Option<String>,
+    /// 1-based inclusive line ranges to emphasize (`{1,3-5}`)
+    highlight: Vec<(usize, usize)>,
+}
+
+impl CodeInfo {
+    fn for_lang(lang: &str) -> Self {
+        CodeInfo { lang: Some(lang.to_string()), ..CodeInfo::default() }
+    }
+}
+
+/// The first word is the language or a `file:PATH` include (unless it is a
+/// `key=value` attribute); comma-separated flags may follow the language, as
+/// in rustdoc's `rust,no_run`.
+/// Recognized flag words and attributes after it are applied and anything
+/// else is ignored. A `{2,4-6}` group anywhere picks lines to highlight.
+fn parse_code_info(info: &str) -> CodeInfo {
+    static RE_LINES: Lazy<Regex> = Lazy::new(|| Regex::new(r"\{([\d\s,-]*)\}").unwrap());
+    let highlight = RE_LINES.captures(info).map(|c| parse_line_ranges(&c[1])).unwrap_or_default();
+    let info = RE_LINES.replace(info, " ");
+    let mut words = info_string_words(&info).into_iter().peekable();
+    let first = words.next_if(|w| !w.split(',').next().unwrap_or_default().contains('='));
+    let mut flags = Vec::new();
+    let mut code_info = match first.as_deref().map(|w| (w, w.strip_prefix("file:"))) {
+        Some((_, Some(file))) => CodeInfo { file: Some(file.to_string()), ..CodeInfo::default() },
+        Some((word, None)) => {
+            let mut parts = word.split(',').map(str::trim).filter(|p| !p.is_empty());
+            let lang = parts.next().map(str::to_string);
+            flags.extend(parts.map(str::to_string));
+            CodeInfo { lang, ..CodeInfo::default() }
+        }
+        None => CodeInfo::default(),
+    };
+    for word in flags.into_iter().chain(words) {
+        match word.split_once('=') {
+            Some(("title" | "filename", value)) if !value.is_empty() => code_info.title = Some(value.to_string()),
+            Some(_) => {}
+            None if word == "wrap" => code_info.wrap = Some(true),
+            None if word == "nowrap" => code_info.wrap = Some(false),
+            None => {}
+        }
+    }
+    code_info.highlight = highlight;
+    code_info
+}
+
+/// `1,3-5` as inclusive ranges; malformed parts are skipped.
+fn parse_line_ranges(spec: &str) -> Vec<(usize, usize)> {
+    spec.split(',')
+        .filter_map(|part| {
+            let part = part.trim();
+            let (start, end) = part.split_once('-').unwrap_or((part, part));
+            let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+            (start <= end).then_some((start, end))
+        })
+        .collect()
+}
+
+/// Splits an info string on whitespace, keeping `"..."` / `'...'` quoted runs
+/// (which may contain spaces) together and dropping the quotes.
+fn info_string_words(info: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote: Option<char> = None;
+    for c in info.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => word.push(c),
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            None => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// Code block languages shown without a syntax definition (as plain text or
+/// rendered otherwise), so never reported as unknown.
+const PLAIN_LANGUAGES: [&str; 7] = ["text", "plain", "plaintext", "ansi", "mermaid", "csv", "tsv"];
+
+/// Warns when a code block's language has no syntax highlighting (the block
+/// is shown as plain text) and returns whether that was the case.
+fn unknown_code_language(lang: &str, source: &Path, opts: &RenderOptions) -> bool {
+    if PLAIN_LANGUAGES.iter().any(|l| l.eq_ignore_ascii_case(lang)) || resolve_syntax(lang, &opts.languages).is_some() {
+        return false;
+    }
+    eprintln!("[haystack] {}: unknown code language {:?}, showing it as plain text", source.display(), lang);
+    true
+}
+
+/// The syntax for a code block language: a `[languages]` alias if one matches
+/// (case-insensitively, its target being a syntax name or token), otherwise
+/// syntect's own token lookup.
+fn resolve_syntax(lang: &str, aliases: &BTreeMap<String, String>) -> Option<&'static SyntaxReference> {
+    match aliases.get(&lang.to_lowercase()) {
+        Some(target) => SYNTAX_SET.find_syntax_by_name(target).or_else(|| SYNTAX_SET.find_syntax_by_token(target)),
+        None => SYNTAX_SET.find_syntax_by_token(lang),
+    }
+}
+
+fn highlight_code(code: &str, info: &CodeInfo, opts: &RenderOptions) -> String {
+    let lang = info.lang.as_deref();
+    let code = tidy_code_blank_lines(code, opts.trim_code, opts.collapse_blank_lines);
+    let code = expand_tabs(&code, opts.tab_width);
+    let (code, anchors) = if opts.code_anchors { extract_code_anchors(&code) } else { (code, Vec::new()) };
+    let code = code.as_str();
     let ss: &SyntaxSet = &SYNTAX_SET;
     let syntax: &SyntaxReference = match lang {
-        Some(l) => ss.find_syntax_by_token(l).unwrap_or_else(|| ss.find_syntax_plain_text()),
+        Some(l) => resolve_syntax(l, &opts.languages).unwrap_or_else(|| ss.find_syntax_plain_text()),
         None => ss.find_syntax_plain_text(),
     };
-    let mut generator = ClassedHTMLGenerator::new_with_class_style(syntax, ss, ClassStyle::Spaced);
-    for line in LinesWithEndings::from(code) {
-        let _ = generator.parse_html_for_line_which_includes_newline(line);
-    }
-    let highlighted = generator.finalize();
+    let line_count = LinesWithEndings::from(code).count();
+    let highlighted = if lang.is_some_and(|l| l.eq_ignore_ascii_case("ansi")) {
+        ansi_to_html(code)
+    } else {
+        let mut generator = ClassedHTMLGenerator::new_with_class_style(syntax, ss, ClassStyle::Spaced);
+        for line in LinesWithEndings::from(code) {
+            let _ = generator.parse_html_for_line_which_includes_newline(line);
+        }
+        generator.finalize()
+    };
+    let is_diff = lang.is_some_and(|l| l.eq_ignore_ascii_case("diff") || l.eq_ignore_ascii_case("patch"));
+    let highlighted = if is_diff || opts.line_numbers || !info.highlight.is_empty() || anchors.iter().any(Option::is_some) {
+        split_highlighted_lines(&highlighted)
+            .into_iter()
+            .zip(code.lines())
+            .enumerate()
+            .map(|(i, (html, raw))| {
+                let class = if is_diff { diff_line_class(raw) } else { "" };
+                let marked = info.highlight.iter().any(|&(start, end)| (start..=end).contains(&(i + 1)));
+                let class = if marked { format!("{} highlighted-line", class) } else { class.to_string() };
+                let id = match anchors.get(i) {
+                    Some(Some(id)) => format!(" id=\"{}\"", escape_html(id)),
+                    _ => String::new(),
+                };
+                format!("<span class=\"line{}\"{}>{}</span>\n", class, id, html)
+            })
+            .collect()
+    } else {
+        highlighted
+    };
     let class_lang = lang.unwrap_or("text");
-    format!("<pre><code class=\"hl language-{}\">{}</code></pre>", class_lang, highlighted)
+    let mut pre_classes = Vec::new();
+    let theme_class = lang.and_then(|l| opts.code_themes.get(&l.to_lowercase())).map(|name| language_theme_class(name));
+    pre_classes.extend(theme_class.as_deref());
+    match info.wrap {
+        Some(true) => pre_classes.push("wrap"),
+        Some(false) => pre_classes.push("nowrap"),
+        None => {}
+    }
+    // The gutter is as wide as the largest number
+    let pre_class = if opts.line_numbers {
+        pre_classes.push("line-numbers");
+        format!(" class=\"{}\" style=\"--line-digits: {}\"", pre_classes.join(" "), line_count.max(1).to_string().len())
+    } else if pre_classes.is_empty() {
+        String::new()
+    } else {
+        format!(" class=\"{}\"", pre_classes.join(" "))
+    };
+    let block = format!("<pre{}><code class=\"hl language-{}\">{}</code></pre>", pre_class, class_lang, highlighted);
+    let block = match opts.collapse_code {
+        Some(max) if line_count > max => {
+            let preview: String = split_highlighted_lines(&highlighted)
+                .into_iter()
+                .take(COLLAPSED_PREVIEW_LINES)
+                .map(|line| line + "\n")
+                .collect();
+            format!(
+                "<div class=\"code-collapse\">\n<pre{} aria-hidden=\"true\"><code class=\"hl language-{}\">{}</code></pre>\n<details>\n<summary>Show {} lines</summary>\n{}\n</details>\n</div>",
+                pre_class, class_lang, preview, line_count, block
+            )
+        }
+        _ => block,
+    };
+    match &info.title {
+        Some(title) => format!(
+            "<div class=\"code-block\">\n<div class=\"code-title\">{}</div>\n{}\n</div>",
+            escape_html(title),
+            block
+        ),
+        None => block,
+    }
+}
+
+/// Text attributes set by ANSI SGR (`ESC[...m`) sequences.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct AnsiStyle {
+    bold: bool,
+    dim: bool,
+    italic: bool,
+    underline: bool,
+    fg: Option<AnsiColor>,
+    bg: Option<AnsiColor>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AnsiColor {
+    /// One of the 16 basic colors (8-15 are the bright ones), styled by class
+    Basic(u8),
+    Rgb(u8, u8, u8),
+}
+
+const ANSI_COLOR_NAMES: [&str; 8] = ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+
+impl AnsiStyle {
+    /// Applies one SGR parameter list; unsupported codes are ignored.
+    fn apply(&mut self, params: &str) {
+        let codes: Vec<u32> = params.split([';', ':']).map(|p| p.parse().unwrap_or(0)).collect();
+        let mut i = 0;
+        while i < codes.len() {
+            match codes[i] {
+                0 => *self = AnsiStyle::default(),
+                1 => self.bold = true,
+                2 => self.dim = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                22 => (self.bold, self.dim) = (false, false),
+                23 => self.italic = false,
+                24 => self.underline = false,
+                c @ 30..=37 => self.fg = Some(AnsiColor::Basic((c - 30) as u8)),
+                c @ 90..=97 => self.fg = Some(AnsiColor::Basic((c - 90 + 8) as u8)),
+                39 => self.fg = None,
+                c @ 40..=47 => self.bg = Some(AnsiColor::Basic((c - 40) as u8)),
+                c @ 100..=107 => self.bg = Some(AnsiColor::Basic((c - 100 + 8) as u8)),
+                49 => self.bg = None,
+                c @ (38 | 48) => {
+                    let (color, used) = extended_ansi_color(&codes[i + 1..]);
+                    i += used;
+                    match c {
+                        38 => self.fg = color,
+                        _ => self.bg = color,
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+
+    /// The opening `<span>` for this style, or `None` for plain text.
+    fn span(&self) -> Option<String> {
+        if *self == AnsiStyle::default() {
+            return None;
+        }
+        let mut classes = Vec::new();
+        let mut style = Vec::new();
+        for (flag, class) in [(self.bold, "ansi-bold"), (self.dim, "ansi-dim"), (self.italic, "ansi-italic"), (self.underline, "ansi-underline")] {
+            if flag {
+                classes.push(class.to_string());
+            }
+        }
+        for (color, prefix, property) in [(self.fg, "ansi-", "color"), (self.bg, "ansi-bg-", "background-color")] {
+            match color {
+                Some(AnsiColor::Basic(n)) => {
+                    let bright = if n >= 8 { "bright-" } else { "" };
+                    classes.push(format!("{}{}{}", prefix, bright, ANSI_COLOR_NAMES[n as usize % 8]));
+                }
+                Some(AnsiColor::Rgb(r, g, b)) => style.push(format!("{}: #{:02x}{:02x}{:02x}", property, r, g, b)),
+                None => {}
+            }
+        }
+        let mut span = String::from("<span");
+        if !classes.is_empty() {
+            span.push_str(&format!(" class=\"{}\"", classes.join(" ")));
+        }
+        if !style.is_empty() {
+            span.push_str(&format!(" style=\"{}\"", style.join("; ")));
+        }
+        span.push('>');
+        Some(span)
+    }
+}
+
+/// The color after a `38`/`48` code (`5;N` or `2;R;G;B`) and how many
+/// parameters it took.
+fn extended_ansi_color(rest: &[u32]) -> (Option<AnsiColor>, usize) {
+    let byte = |i: usize| rest.get(i).map_or(0, |&v| v.min(255) as u8);
+    match rest.first() {
+        Some(5) => {
+            let n = byte(1);
+            let color = match n {
+                0..=15 => AnsiColor::Basic(n),
+                16..=231 => {
+                    let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+                    let n = n - 16;
+                    AnsiColor::Rgb(level(n / 36), level(n / 6 % 6), level(n % 6))
+                }
+                _ => {
+                    let gray = 8 + (n - 232) * 10;
+                    AnsiColor::Rgb(gray, gray, gray)
+                }
+            };
+            (Some(color), 2)
+        }
+        Some(2) => (Some(AnsiColor::Rgb(byte(1), byte(2), byte(3))), 4),
+        _ => (None, rest.len()),
+    }
+}
+
+/// Terminal output to HTML for ```` ```ansi ```` blocks: SGR color and style
+/// sequences become spans, every other escape sequence is dropped. Spans
+/// never cross a line break.
+fn ansi_to_html(code: &str) -> String {
+    let mut out = String::with_capacity(code.len());
+    let mut style = AnsiStyle::default();
+    let mut text = String::new();
+    let flush = |out: &mut String, text: &mut String, style: &AnsiStyle| {
+        if text.is_empty() {
+            return;
+        }
+        match style.span() {
+            Some(span) => out.push_str(&format!("{}{}</span>", span, escape_html(text))),
+            None => out.push_str(&escape_html(text)),
+        }
+        text.clear();
+    };
+    let mut chars = code.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                // CSI: parameters, then one final byte in @..~
+                Some('[') => {
+                    let mut params = String::new();
+                    let mut last = None;
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            last = Some(c);
+                            break;
+                        }
+                        params.push(c);
+                    }
+                    if last == Some('m') {
+                        flush(&mut out, &mut text, &style);
+                        style.apply(&params);
+                    }
+                }
+                // OSC (titles, hyperlinks): up to BEL or ESC \
+                Some(']') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            },
+            '\n' => {
+                flush(&mut out, &mut text, &style);
+                out.push('\n');
+            }
+            c if c.is_control() && c != '\t' => {}
+            c => text.push(c),
+        }
+    }
+    flush(&mut out, &mut text, &style);
+    out
+}
+
+/// Class for one line of a `diff`/`patch` block. File headers (`+++`,
+/// `---`, `diff --git`, `index ...`) get none.
+fn diff_line_class(raw: &str) -> &'static str {
+    if raw.starts_with("@@") {
+        " diff-hunk"
+    } else if raw.starts_with('+') && !raw.starts_with("+++") {
+        " diff-add"
+    } else if raw.starts_with('-') && !raw.starts_with("---") {
+        " diff-del"
+    } else if raw.is_empty() || raw.starts_with(' ') {
+        " diff-ctx"
+    } else {
+        ""
+    }
+}
+
+/// Removes trailing `@anchor ID` comments (`// @anchor ID`, `# @anchor ID`,
+/// `-- @anchor ID`, `/* @anchor ID */`) from code lines. Returns the cleaned
+/// code and, per line, the anchor id it carried.
+fn extract_code_anchors(code: &str) -> (String, Vec<Option<String>>) {
+    static RE_ANCHOR: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r"\s*(?://|#|--|;+|/\*)\s*@anchor\s+([A-Za-z][\w-]*)\s*(?:\*/)?\s*$").unwrap()
+    });
+    let mut out = String::with_capacity(code.len());
+    let mut anchors = Vec::new();
+    for line in code.lines() {
+        match RE_ANCHOR.captures(line) {
+            Some(caps) => {
+                out.push_str(&line[..caps.get(0).unwrap().start()]);
+                anchors.push(Some(caps[1].to_string()));
+            }
+            None => {
+                out.push_str(line);
+                anchors.push(None);
+            }
+        }
+        out.push('\n');
+    }
+    if !code.ends_with('\n') {
+        out.pop();
+    }
+    (out, anchors)
+}
+
+/// Lines of a collapsed code block shown above its "Show N lines" toggle.
+const COLLAPSED_PREVIEW_LINES: usize = 5;
+
+/// Splits syntect's classed HTML into one fragment per source line, closing
+/// spans that are open at each line break and reopening them on the next line
+/// so every fragment is balanced on its own. Newlines are dropped.
+fn split_highlighted_lines(html: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut open: Vec<&str> = Vec::new();
+    let mut line = String::new();
+    let mut has_text = false;
+    let mut rest = html;
+    while !rest.is_empty() {
+        if rest.starts_with('<') {
+            let end = rest.find('>').map_or(rest.len(), |i| i + 1);
+            let tag = &rest[..end];
+            if tag.starts_with("</") {
+                open.pop();
+            } else {
+                open.push(tag);
+            }
+            line.push_str(tag);
+            rest = &rest[end..];
+        } else {
+            let end = rest.find(['<', '\n']).unwrap_or(rest.len());
+            if end == 0 {
+                // A line break: close this line and reopen the spans on the next
+                line.push_str(&"</span>".repeat(open.len()));
+                lines.push(std::mem::take(&mut line));
+                line = open.concat();
+                has_text = false;
+                rest = &rest[1..];
+            } else {
+                line.push_str(&rest[..end]);
+                has_text = true;
+                rest = &rest[end..];
+            }
+        }
+    }
+    if has_text {
+        line.push_str(&"</span>".repeat(open.len()));
+        lines.push(line);
+    }
+    lines
+}
+
+/// Replaces each tab with spaces up to the next multiple of `width` columns, so
+/// code lines up the same regardless of the browser's tab size.
+fn expand_tabs(code: &str, width: usize) -> String {
+    if width == 0 || !code.contains('\t') {
+        return code.to_string();
+    }
+    let mut out = String::with_capacity(code.len());
+    let mut column = 0;
+    for c in code.chars() {
+        match c {
+            '\t' => {
+                let pad = width - column % width;
+                out.extend(std::iter::repeat_n(' ', pad));
+                column += pad;
+            }
+            '\n' => {
+                out.push(c);
+                column = 0;
+            }
+            _ => {
+                out.push(c);
+                column += 1;
+            }
+        }
+    }
+    out
 }
 
-fn highlight_code_blocks_in_html(input_html: &str) -> String {
+/// Optionally drops blank lines at the start/end of a code block and squeezes
+/// runs of three or more blank lines down to one. Whitespace-only lines count
+/// as blank.
+fn tidy_code_blank_lines(code: &str, trim: bool, collapse: bool) -> String {
+    if !trim && !collapse {
+        return code.to_string();
+    }
+    let mut lines: Vec<&str> = code.lines().collect();
+    if trim {
+        let start = lines.iter().position(|l| !l.trim().is_empty()).unwrap_or(lines.len());
+        let end = lines.iter().rposition(|l| !l.trim().is_empty()).map_or(start, |i| i + 1);
+        lines = lines[start..end].to_vec();
+    }
+    if collapse {
+        let mut out: Vec<&str> = Vec::with_capacity(lines.len());
+        let mut blanks = 0;
+        for (i, line) in lines.iter().enumerate() {
+            if line.trim().is_empty() {
+                blanks += 1;
+                let run_ends = lines.get(i + 1).is_none_or(|next| !next.trim().is_empty());
+                if run_ends {
+                    let keep = if blanks >= 3 { 1 } else { blanks };
+                    out.extend(std::iter::repeat_n("", keep));
+                    blanks = 0;
+                }
+            } else {
+                out.push(line);
+            }
+        }
+        lines = out;
+    }
+    let mut out = lines.join("\n");
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out
+}
+
+/// Highlights the code blocks of converted org HTML. Also returns how many
+/// were in a language there's no highlighting for.
+fn highlight_code_blocks_in_html(input_html: &str, source: &Path, opts: &RenderOptions) -> (String, usize) {
     static RE_MD: Lazy<Regex> = Lazy::new(|| {
         Regex::new(r#"(?s)<pre><code class=\"language-([A-Za-z0-9_+\-.#]+)\">(.*?)</code></pre>"#).unwrap()
     });
@@ -711,20 +6035,468 @@ fn highlight_code_blocks_in_html(input_html: &str) -> String {
             .replace("&quot;", "\"")
             .replace("&#39;", "'")
     };
+    let mut unknown = 0;
 
     let tmp = RE_MD.replace_all(input_html, |caps: &regex::Captures| {
         let lang = caps.get(1).map(|m| m.as_str()).unwrap_or("text");
         let code_escaped = caps.get(2).map(|m| m.as_str()).unwrap_or("");
         let code = unescape(code_escaped);
-        highlight_code(&code, Some(lang))
+        unknown += usize::from(unknown_code_language(lang, source, opts));
+        highlight_code(&code, &CodeInfo::for_lang(lang), opts)
     });
 
     let tmp = RE_ORG.replace_all(&tmp, |caps: &regex::Captures| {
         let lang = caps.get(1).map(|m| m.as_str()).unwrap_or("text");
         let code_escaped = caps.get(2).map(|m| m.as_str()).unwrap_or("");
+        if opts.mermaid && lang.eq_ignore_ascii_case("mermaid") {
+            return mermaid_html(code_escaped);
+        }
         let code = unescape(code_escaped);
-        highlight_code(&code, Some(lang))
+        unknown += usize::from(unknown_code_language(lang, source, opts));
+        highlight_code(&code, &CodeInfo::for_lang(lang), opts)
     });
 
-    tmp.into_owned()
+    (tmp.into_owned(), unknown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Render options as `haystack build <flags>` would set them.
+    fn options(flags: &[&str]) -> RenderOptions {
+        let args = ["haystack", "build"].iter().chain(flags);
+        match Cli::try_parse_from(args).unwrap().command {
+            Commands::Build { render, .. } => render.options(&Config::default()).unwrap(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn markdown(input: &str, flags: &[&str]) -> ConvertedPage {
+        convert_markdown_to_html(input, Path::new("page.md"), &options(flags)).unwrap()
+    }
+
+    fn org(input: &str, flags: &[&str]) -> ConvertedPage {
+        convert_org_to_html(input, Path::new("page.org"), &options(flags))
+    }
+
+    #[test]
+    fn trim_code_drops_leading_and_trailing_blank_lines() {
+        assert_eq!(tidy_code_blank_lines("\n  \nfn a() {}\n\n  b\n\n\t\n", true, false), "fn a() {}\n\n  b\n");
+        assert_eq!(tidy_code_blank_lines("\n\n", true, false), "");
+        assert_eq!(tidy_code_blank_lines("\nx\n\n", false, false), "\nx\n\n");
+        let page = markdown("```text\n\n\nhello\n\n```\n", &["--trim-code"]);
+        assert!(page.body.contains("<span class=\"text plain\">hello\n</span></code>"), "{}", page.body);
+        let page = markdown("```text\n\nhello\n\n```\n", &[]);
+        assert!(page.body.contains("<span class=\"text plain\">\nhello\n\n</span></code>"), "{}", page.body);
+    }
+
+    #[test]
+    fn scoped_theme_css_is_sorted_and_stable() {
+        let css = ".code { color: #111; }\n.string { color: #222; }\n/* c */ .comment, .block { color: #333; }\n";
+        let scoped = scope_syntect_css(css, "html", Some("pre"));
+        assert_eq!(
+            scoped,
+            "pre {\n color: #111; }\nhtml .comment, html .block{\n color: #333; }\nhtml .string{\n color: #222; }\n"
+        );
+        let theme = THEME_SET.themes.get("InspiredGitHub").unwrap();
+        let runs: Vec<String> = (0..3)
+            .map(|_| scope_syntect_css(&css_for_theme_with_class_style(theme, ClassStyle::Spaced).unwrap(), "html", None))
+            .collect();
+        assert!(runs.windows(2).all(|w| w[0] == w[1]));
+        let selectors: Vec<&str> = runs[0].lines().filter_map(|l| l.strip_suffix('{')).collect();
+        assert!(selectors.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn content_types_declare_utf8_for_text() {
+        assert_eq!(content_type_for(Path::new("index.html")), HTML_CONTENT_TYPE);
+        assert_eq!(content_type_for(Path::new("style.css")), "text/css; charset=utf-8");
+        assert_eq!(content_type_for(Path::new("app.js")), "text/javascript; charset=utf-8");
+        assert_eq!(content_type_for(Path::new("feed.json")), "application/json; charset=utf-8");
+        assert_eq!(content_type_for(Path::new("logo.png")), "image/png");
+    }
+
+    #[test]
+    fn excerpt_is_plain_text_without_headings() {
+        let input = "# Title\n\nSome *emphasis*, `code` and [a link](x.html).\n\n- one\n- **two**\n\n```\nskipped\n```\n";
+        assert_eq!(
+            page_excerpt(input, &options(&[])).as_deref(),
+            Some("Some emphasis, code and a link. one two")
+        );
+        assert_eq!(page_excerpt(input, &options(&["--excerpt-length", "20"])).as_deref(), Some("Some emphasis, code…"));
+        assert_eq!(page_excerpt("# Only a heading\n", &options(&[])), None);
+    }
+
+    #[test]
+    fn org_html_title_wins_over_title() {
+        let input = "#+TITLE: Plain\n* Headline\n#+HTML_TITLE: For the browser\n";
+        assert_eq!(extract_title_from_org(input).as_deref(), Some("For the browser"));
+        assert_eq!(extract_title_from_org("#+title: Plain\n* Headline\n").as_deref(), Some("Plain"));
+        assert_eq!(extract_title_from_org("* Headline\n#+TITLE: Plain\n").as_deref(), Some("Headline"));
+    }
+
+    #[test]
+    fn colliding_sources_keep_html_then_md_then_org() {
+        let src = std::env::temp_dir().join(format!("haystack-collide-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&src);
+        fs::create_dir_all(&src).unwrap();
+        for name in ["a.md", "a.org", "b.org", "c.md", "c.html"] {
+            fs::write(src.join(name), "x\n").unwrap();
+        }
+        let mut kept = collect_sources(&src, false, false).unwrap();
+        kept.sort();
+        assert_eq!(kept, ["a.md", "b.org", "c.html"].map(|n| src.join(n)));
+        let err = collect_sources(&src, true, false).unwrap_err();
+        assert!(err.is::<StrictWarnings>());
+        assert!(err.to_string().contains("c.html is produced by"), "{}", err);
+        fs::remove_dir_all(&src).unwrap();
+    }
+
+    #[test]
+    fn org_checkboxes_and_progress_cookies() {
+        let input = "* Tasks [1/3] [33%]\n- [X] done\n- [ ] todo\n- [-] partial\n";
+        let page = org(input, &[]);
+        assert!(page.body.contains(r#"Tasks <span class="progress-cookie">1/3</span> <span class="progress-cookie">33%</span></h1>"#), "{}", page.body);
+        assert!(page.body.contains(r#"<input disabled="" type="checkbox" checked=""/> done"#), "{}", page.body);
+        assert!(page.body.contains(r#"<input disabled="" type="checkbox"/> todo"#), "{}", page.body);
+        assert!(page.body.contains(r#"<input disabled="" type="checkbox" class="partial" aria-checked="mixed"/> partial"#), "{}", page.body);
+        assert!(!page.meta.has_tasks);
+
+        let page = org(input, &["--interactive-tasks"]);
+        assert!(page.body.contains(r#"<input type="checkbox" class="task" data-task="0" checked=""/> done"#), "{}", page.body);
+        assert!(page.body.contains(r#"<input type="checkbox" class="task partial" data-task="2"/> partial"#), "{}", page.body);
+        assert!(page.meta.has_tasks);
+    }
+
+    #[test]
+    fn code_block_title_from_info_string() {
+        assert_eq!(parse_code_info("rust title=main.rs").title.as_deref(), Some("main.rs"));
+        assert_eq!(parse_code_info(r#"rust title="my file.rs""#).title.as_deref(), Some("my file.rs"));
+        assert_eq!(parse_code_info("sh filename='run all.sh' wrap").title.as_deref(), Some("run all.sh"));
+        assert_eq!(parse_code_info("rust title=").title, None);
+        let page = markdown("```rust title=\"a <b>.rs\"\nfn main() {}\n```\n", &[]);
+        assert!(page.body.contains("<div class=\"code-title\">a &lt;b&gt;.rs</div>"), "{}", page.body);
+    }
+
+    #[test]
+    fn slugify_non_ascii_headings() {
+        assert_eq!(slugify("Café Crème"), "cafe-creme");
+        assert_eq!(slugify("Straße & Ærø"), "strasse-aero");
+        assert_eq!(slugify("Rust 🦀 tips"), "rust-tips");
+        assert_eq!(slugify("   "), "section");
+        for heading in ["日本語の見出し", "🦀🚀"] {
+            let slug = slugify(heading);
+            assert!(slug.starts_with("h-") && slug.len() == 10, "{}", slug);
+            assert_eq!(slug, slugify(heading));
+        }
+        assert_ne!(slugify("日本語"), slugify("中文"));
+    }
+
+    #[test]
+    fn org_admonition_blocks() {
+        let input = "#+begin_note\nBody *x*\n#+end_note\n#+begin_warning Careful <now>\nW\n#+end_warning\n#+begin_TIP\nT\n#+end_TIP\n#+begin_aside\nA\n#+end_aside\n";
+        let body = org(input, &[]).body;
+        assert!(body.contains(r#"<div class="admonition note"><p class="admonition-title">Note</p><p>Body <b>x</b></p></div>"#), "{}", body);
+        assert!(body.contains(r#"<div class="admonition warning"><p class="admonition-title">Careful &lt;now&gt;</p><p>W</p></div>"#), "{}", body);
+        assert!(body.contains(r#"<div class="admonition tip"><p class="admonition-title">Tip</p><p>T</p></div>"#), "{}", body);
+        assert_eq!(body.matches("admonition-title").count(), 3);
+    }
+
+    #[test]
+    fn toc_filters_levels_and_nests() {
+        let input = "# Title\n\n## One\n\n### One A\n\n#### Deep\n\n### One B\n\n## Two\n";
+        let body = markdown(input, &["--toc"]).body;
+        let toc = &body[body.find("<nav class=\"toc\"").unwrap()..body.find("</nav>").unwrap()];
+        assert_eq!(
+            toc,
+            "<nav class=\"toc\" aria-label=\"Contents\">\n<ul>\n<li><a href=\"#one\">One</a>\n<ul>\n<li><a href=\"#one-a\">One A</a></li>\n<li><a href=\"#one-b\">One B</a></li>\n</ul>\n</li>\n<li><a href=\"#two\">Two</a></li>\n</ul>\n"
+        );
+        assert!(body.find("</h1>").unwrap() < body.find("<nav class=\"toc\"").unwrap());
+
+        let body = markdown(input, &["--toc", "--toc-min-level", "3", "--toc-max-level", "4"]).body;
+        assert!(body.contains("<li><a href=\"#one-a\">One A</a>\n<ul>\n<li><a href=\"#deep\">Deep</a></li>\n</ul>\n</li>"), "{}", body);
+        assert!(!body.contains("href=\"#two\""), "{}", body);
+
+        let body = markdown(input, &["--toc", "--toc-min-headings", "5"]).body;
+        assert!(!body.contains("class=\"toc\""), "{}", body);
+    }
+
+    #[test]
+    fn bom_and_crlf_sources_parse_like_lf() {
+        assert_eq!(normalize_source("\u{feff}a\r\nb\r\n".to_string()), "a\nb\n");
+        assert_eq!(normalize_source("a\rb\n".to_string()), "a\rb\n");
+        let dir = std::env::temp_dir().join(format!("haystack-crlf-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let md = dir.join("page.md");
+        fs::write(&md, "\u{feff}---\r\ntitle: Windows\r\n---\r\n\r\n```text\r\none\r\ntwo\r\n```\r\n").unwrap();
+        let page = convert_file_body(&md, &options(&[])).unwrap();
+        assert_eq!(page.meta.title.as_deref(), Some("Windows"));
+        assert!(!page.body.contains('\r') && !page.body.contains("title:"), "{}", page.body);
+        let org = dir.join("page.org");
+        fs::write(&org, "\u{feff}#+TITLE: Org on Windows\r\n* Heading\r\n").unwrap();
+        let page = convert_file_body(&org, &options(&[])).unwrap();
+        assert_eq!(page.meta.title.as_deref(), Some("Org on Windows"));
+        assert!(!page.body.contains('\r'), "{}", page.body);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn content_hashes_are_stable_per_algorithm() {
+        let cases = [
+            (HashAlgorithm::Sha256, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+            (HashAlgorithm::Xxhash, "78af5f94892f3950"),
+            (HashAlgorithm::Blake3, "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"),
+        ];
+        for (algorithm, digest) in cases {
+            assert_eq!(content_hash(algorithm, b"abc"), digest, "{}", algorithm.name());
+        }
+        assert_eq!(content_hash(HashAlgorithm::Xxhash, b""), "2d06800538d394c2");
+    }
+
+    #[test]
+    fn org_caption_figures() {
+        let input = "#+CAPTION: A *cat*\n[[file:cat.png]]\n\n#+CAPTION: Sizes\n| a | b |\n\n#+CAPTION: Listing\n#+begin_src text\nx\n#+end_src\n";
+        let body = org(input, &[]).body;
+        assert!(body.contains("<figure>\n<img src=\"cat.png\" alt=\"cat.png\">\n<figcaption>A <b>cat</b></figcaption>\n</figure>"), "{}", body);
+        assert!(body.contains("<figcaption>Sizes</figcaption>"), "{}", body);
+        assert!(!body.contains("Listing"), "{}", body);
+        assert_eq!(body.matches("<figure").count(), 2);
+
+        // Numbered figures open with their id on a line of its own, like unnumbered ones
+        let body = org(input, &["--figures"]).body;
+        assert!(body.contains("<figure id=\"figure-1\">\n<img src=\"cat.png\""), "{}", body);
+        assert!(body.contains("<figcaption>Figure 1: A <b>cat</b></figcaption>"), "{}", body);
+        assert!(body.contains("<figure>\n<table>"), "{}", body);
+        assert!(!body.contains("figure-2"), "{}", body);
+    }
+
+    #[test]
+    fn excerpt_separator_present_and_absent() {
+        let opts = options(&["--excerpt-length", "12"]);
+        let input = "Intro text that runs long.\n\n<!--more-->\n\nRest of the page.\n";
+        assert_eq!(page_excerpt(input, &opts).as_deref(), Some("Intro text that runs long."));
+        let input = "Intro text that runs long.\n\n```\n<!--more-->\n```\n";
+        assert_eq!(page_excerpt(input, &opts).as_deref(), Some("Intro text…"));
+        let input = "Intro `<!--more-->` text\n";
+        assert_eq!(page_excerpt(input, &opts).as_deref(), Some("Intro…"));
+
+        let opts = RenderOptions { excerpt_separator: None, ..opts };
+        let input = "Intro text that runs long.\n\n<!--more-->\n";
+        assert_eq!(page_excerpt(input, &opts).as_deref(), Some("Intro text…"));
+    }
+
+    #[test]
+    fn org_headline_ids() {
+        let input = "* First Heading\n** Sub /one/\n:PROPERTIES:\n:CUSTOM_ID: custom\n:END:\n* First Heading\n* Tasks [1/2]\n";
+        let body = org(input, &[]).body;
+        assert!(body.contains(r#"<h1 id="first-heading">First Heading</h1>"#), "{}", body);
+        assert!(body.contains(r#"<h2 id="custom">Sub <i>one</i></h2>"#), "{}", body);
+        assert!(body.contains(r#"<h1 id="first-heading-1">First Heading</h1>"#), "{}", body);
+        assert!(body.contains(r#"<h1 id="tasks">Tasks "#), "{}", body);
+
+        let body = org(input, &["--anchor-style", "hash"]).body;
+        assert!(body.contains(r##"<h2 id="custom"><a class="anchor anchor-hash" href="#custom" aria-hidden="true">#</a>Sub"##), "{}", body);
+    }
+
+    #[test]
+    fn math_renders_katex_markup() {
+        let input = "Inline $a<b$ and\n\n$$\\sum_i x$$\n\nIt costs $5 and $7 total\n";
+        let page = markdown(input, &["--math"]);
+        assert!(page.body.contains(r#"<span class="math inline">\(a&lt;b\)</span>"#), "{}", page.body);
+        assert!(page.body.contains(r#"<div class="math display">\[\sum_i x\]</div>"#), "{}", page.body);
+        assert!(page.body.contains("<p>It costs $5 and $7 total</p>"), "{}", page.body);
+        assert!(!page.body.contains("<span class=\"math inline\">\\(5 and "), "{}", page.body);
+        assert_eq!(page.body.matches("class=\"math inline\"").count(), 1, "{}", page.body);
+        assert!(page.meta.has_math);
+        let html = wrap_html_page(page.body, &page.meta, &ThemeConfig::default(), &options(&["--math"]), None);
+        assert!(html.contains("katex"), "{}", html);
+
+        let page = markdown(input, &[]);
+        assert!(!page.body.contains("class=\"math"), "{}", page.body);
+        assert!(!page.meta.has_math);
+    }
+
+    #[test]
+    fn comma_separated_info_string() {
+        let info = parse_code_info("rust,no_run");
+        assert_eq!(info.lang.as_deref(), Some("rust"));
+        let info = parse_code_info("rust,ignore,title=lib.rs,nowrap");
+        assert_eq!(info.lang.as_deref(), Some("rust"));
+        assert_eq!(info.title.as_deref(), Some("lib.rs"));
+        assert_eq!(info.wrap, Some(false));
+        assert_eq!(parse_code_info(",rust").lang.as_deref(), Some("rust"));
+        let info = parse_code_info(r#"python title="x.py""#);
+        assert_eq!((info.lang.as_deref(), info.title.as_deref()), (Some("python"), Some("x.py")));
+        assert_eq!(parse_code_info("title=x.py").lang, None);
+        let body = markdown("```rust,no_run\nfn main() {}\n```\n", &[]).body;
+        assert!(body.contains("class=\"hl language-rust\""), "{}", body);
+    }
+
+    #[test]
+    fn line_highlight_ranges() {
+        assert_eq!(parse_line_ranges("2,4"), [(2, 2), (4, 4)]);
+        assert_eq!(parse_line_ranges(" 1 , 3-5 "), [(1, 1), (3, 5)]);
+        assert_eq!(parse_line_ranges("5-3,x,,7-"), []);
+        assert_eq!(parse_code_info("rust {2,4-5} title=a.rs").highlight, [(2, 2), (4, 5)]);
+        assert_eq!(parse_code_info("rust{1}").lang.as_deref(), Some("rust"));
+        let body = markdown("```text {2,4-5}\na\nb\nc\nd\ne\nf\n```\n", &[]).body;
+        let marked: Vec<usize> = body
+            .split("<span class=\"line")
+            .skip(1)
+            .enumerate()
+            .filter(|(_, l)| l.contains("highlighted-line"))
+            .map(|(i, _)| i + 1)
+            .collect();
+        assert_eq!(marked, [2, 4, 5], "{}", body);
+    }
+
+    #[test]
+    fn diff_line_classes() {
+        assert_eq!(diff_line_class("@@ -1,2 +1,2 @@"), " diff-hunk");
+        assert_eq!(diff_line_class("+added"), " diff-add");
+        assert_eq!(diff_line_class("-removed"), " diff-del");
+        assert_eq!(diff_line_class(" kept"), " diff-ctx");
+        assert_eq!(diff_line_class(""), " diff-ctx");
+        assert_eq!(diff_line_class("+++ b/file"), "");
+        assert_eq!(diff_line_class("--- a/file"), "");
+        assert_eq!(diff_line_class("diff --git a/file b/file"), "");
+        let body = markdown("```diff\n--- a/x\n+++ b/x\n@@ -1 +1 @@\n-old\n+new\n same\n```\n", &[]).body;
+        let classes: Vec<&str> = body
+            .split("<span class=\"line")
+            .skip(1)
+            .map(|l| l.split('"').next().unwrap_or_default())
+            .collect();
+        assert_eq!(classes, ["", "", " diff-hunk", " diff-del", " diff-add", " diff-ctx"], "{}", body);
+    }
+
+    #[test]
+    fn ansi_red_then_reset() {
+        assert_eq!(ansi_to_html("\x1b[31merror\x1b[0m: <done>"), "<span class=\"ansi-red\">error</span>: &lt;done&gt;");
+        assert_eq!(ansi_to_html("\x1b[1;31mA\x1b[39mB\x1b[m"), "<span class=\"ansi-bold ansi-red\">A</span><span class=\"ansi-bold\">B</span>");
+        assert_eq!(ansi_to_html("\x1b[38;2;255;0;0mx\x1b[0m"), "<span style=\"color: #ff0000\">x</span>");
+        assert_eq!(ansi_to_html("\x1b]0;title\x07plain\x1b[2K"), "plain");
+        let body = markdown("```ansi\n\x1b[31mred\x1b[0m\n```\n", &[]).body;
+        assert!(body.contains("<span class=\"ansi-red\">red</span>"), "{}", body);
+    }
+
+    #[test]
+    fn only_external_links_open_in_a_new_tab() {
+        assert!(is_external_link("https://rust-lang.org/learn", None));
+        assert!(!is_external_link("guide/intro.html", None));
+        assert!(!is_external_link("mailto:me@example.com", None));
+        assert!(!is_external_link("HTTPS://Example.com/docs", Some("https://example.com")));
+        assert!(is_external_link("https://other.example.com/", Some("https://example.com")));
+
+        let input = "[intro](guide/intro.html) and [rust](https://rust-lang.org/)\n";
+        let flags = ["--external-links-new-tab"];
+        let body = markdown(input, &flags).body;
+        assert!(body.contains("<a href=\"guide/intro.html\">intro</a>"), "{}", body);
+        assert!(body.contains(&format!("<a href=\"https://rust-lang.org/\"{}>rust</a>", EXTERNAL_LINK_ATTRS)), "{}", body);
+        let body = markdown(input, &[]).body;
+        assert!(!body.contains("target=\"_blank\""), "{}", body);
+
+        let body = org("[[file:guide/intro.org][intro]] and [[https://rust-lang.org/][rust]]\n", &flags).body;
+        assert_eq!(body.matches("target=\"_blank\"").count(), 1, "{}", body);
+        assert!(body.contains("href=\"https://rust-lang.org/\"") && body.contains(EXTERNAL_LINK_ATTRS), "{}", body);
+    }
+
+    #[test]
+    fn tables_are_wrapped_in_a_scrolling_div() {
+        let input = "| a | b |\n|---|---|\n| 1 | 2 |\n";
+        let body = markdown(input, &[]).body;
+        let start = body.find(TABLE_WRAP_START).expect(&body);
+        assert!(body[start + TABLE_WRAP_START.len()..].starts_with("<table>"), "{}", body);
+        assert!(body.trim_end().ends_with("</table>\n</div>"), "{}", body);
+        let body = markdown(input, &["--no-table-wrap"]).body;
+        assert!(!body.contains("table-wrap") && body.contains("<table>"), "{}", body);
+    }
+
+    #[test]
+    fn feed_orders_entries_by_instant_across_offsets() {
+        assert_eq!(feed_instant("1970-01-01T00:00:00Z"), (0, 0));
+        assert_eq!(feed_instant("2000-03-01T00:00:00Z"), (951_868_800, 0));
+        assert_eq!(feed_instant("1970-01-01T01:00:00+01:00"), (0, 0));
+        assert_eq!(feed_instant("1969-12-31T19:30:00.25-04:30"), (0, 250_000_000));
+        let entry = |path: &str, updated: &str| FeedEntry {
+            title: path.to_string(),
+            path: path.to_string(),
+            updated: feed_timestamp(updated).unwrap(),
+            summary: None,
+        };
+        let entries = vec![
+            // 10:00 UTC, 11:00 UTC and 12:00 UTC; as strings the order is reversed
+            entry("a.html", "2024-05-01T15:00:00+05:00"),
+            entry("b.html", "2024-05-01T11:00:00Z"),
+            entry("c.html", "2024-05-01T07:00:00-05:00"),
+        ];
+        let xml = atom_feed("https://example.com", "Site", entries, 2);
+        let c = xml.find("c.html").unwrap();
+        let b = xml.find("b.html").unwrap();
+        assert!(c < b && !xml.contains("a.html"), "{}", xml);
+        assert!(xml.contains("<link rel=\"self\" href=\"https://example.com/feed.xml\"/>\n<updated>2024-05-01T07:00:00-05:00</updated>"), "{}", xml);
+    }
+
+    #[test]
+    fn accepted_encodings_follow_q_values() {
+        use ContentEncoding::{Brotli, Gzip};
+        assert_eq!(accepted_encodings(None), []);
+        assert_eq!(accepted_encodings(Some("gzip, deflate, br")), [Brotli, Gzip]);
+        assert_eq!(accepted_encodings(Some("br;q=0, *")), [Gzip]);
+        assert_eq!(accepted_encodings(Some("*;q=0.5, gzip;q=0")), [Brotli]);
+        assert_eq!(accepted_encodings(Some("br;q=0.5, gzip")), [Gzip, Brotli]);
+        assert_eq!(accepted_encodings(Some("gzip;q=0.8, br;q=0.8")), [Brotli, Gzip]);
+        assert_eq!(accepted_encodings(Some("identity")), []);
+        assert_eq!(accepted_encodings(Some("*;q=0")), []);
+    }
+
+    #[test]
+    fn page_title_is_escaped() {
+        let page = markdown("---\ntitle: Vec<T> & \"friends\"\n---\nBody\n", &[]);
+        let html = wrap_html_page(page.body, &page.meta, &ThemeConfig::default(), &options(&[]), None);
+        assert!(html.contains("<title>Vec&lt;T&gt; &amp; &quot;friends&quot;</title>"), "{}", html);
+    }
+
+    #[test]
+    fn unknown_languages_and_empty_alts_are_counted() {
+        let page = markdown("![](a.png) ![a chart](b.png) ![*](c.png)\n\n```nosuchlang\nx\n```\n\n```rust\n```\n\n```text\n```\n", &[]);
+        assert_eq!((page.meta.unknown_languages, page.meta.empty_alts), (1, 1));
+        let page = org("#+begin_src nosuchlang\nx\n#+end_src\n#+begin_src rust\nx\n#+end_src\n[[file:a.png]]\n", &[]);
+        assert_eq!((page.meta.unknown_languages, page.meta.empty_alts), (1, 0));
+    }
+
+    #[test]
+    fn language_aliases_remap_tokens() {
+        let aliases = BTreeMap::from([("ts".to_string(), "TypeScript".to_string()), ("sh".to_string(), "Bash".to_string())]);
+        let name = |lang: &str| resolve_syntax(lang, &aliases).map(|s| s.name.as_str());
+        assert_eq!(name("ts"), Some("TypeScript"));
+        assert_eq!(name("SH"), Some("Bourne Again Shell (bash)"));
+        assert_eq!(name("rs"), SYNTAX_SET.find_syntax_by_token("rs").map(|s| s.name.as_str()));
+        assert_eq!(name("rs"), Some("Rust"));
+
+        let toml = "[languages]\nTS = \"TypeScript\"\nsh = \"Bash\"\n";
+        let config: Config = toml::from_str(toml).unwrap();
+        let args = Cli::try_parse_from(["haystack", "build"]).unwrap();
+        let Commands::Build { render, .. } = args.command else { unreachable!() };
+        let opts = render.options(&config).unwrap();
+        assert_eq!(opts.languages.get("ts").map(String::as_str), Some("TypeScript"));
+        assert_eq!(opts.languages.get("sh").map(String::as_str), Some("Bourne Again Shell (bash)"));
+    }
+
+    #[test]
+    fn repeated_markdown_headings_get_unique_ids() {
+        let body = markdown("## intro\n\n## intro\n", &[]).body;
+        assert!(body.contains("<h2 id=\"intro\">intro</h2>"), "{}", body);
+        assert!(body.contains("<h2 id=\"intro-1\">intro</h2>"), "{}", body);
+
+        let body = markdown("## intro\n\n## intro\n", &["--anchor-style", "hash"]).body;
+        assert!(body.contains(r##"<h2 id="intro"><a class="anchor anchor-hash" href="#intro""##), "{}", body);
+        assert!(body.contains(r##"<h2 id="intro-1"><a class="anchor anchor-hash" href="#intro-1""##), "{}", body);
+    }
+
+    #[test]
+    fn include_paths_normalize_to_watched_paths() {
+        assert_eq!(normalize_path(Path::new("src/a/../shared/./x.rs")), PathBuf::from("src/shared/x.rs"));
+        assert_eq!(normalize_path(Path::new("../x.rs")), PathBuf::from("../x.rs"));
+    }
 }